@@ -0,0 +1,83 @@
+use hyper::Uri;
+
+use crate::log::{self, clf};
+
+/// The per-request log: one Common Log Format line per request under
+/// --log-format clf, otherwise the proxy's own `[req ...]` lines.
+/// Requests for --access-log-exclude-paths are still proxied and counted in
+/// metrics, they just don't show up here.
+#[derive(Debug)]
+pub struct AccessLog {
+    format: log::Format,
+    exclude: PathFilter,
+}
+
+impl AccessLog {
+    pub fn new(format: log::Format, exclude: PathFilter) -> AccessLog {
+        AccessLog { format, exclude }
+    }
+
+    /// Whether `uri` gets per-request lines at all
+    pub fn records(&self, uri: &Uri) -> bool {
+        !self.exclude.is_excluded(uri.path())
+    }
+
+    pub fn is_clf(&self) -> bool {
+        self.format == log::Format::Clf
+    }
+
+    /// Write the CLF line for a finished request to `uri`
+    pub fn write(&self, uri: &Uri, entry: &clf::Entry) {
+        if self.is_clf() && self.records(uri) {
+            log::emit(&clf::format(entry));
+        }
+    }
+}
+
+/// Paths whose per-request log lines are suppressed, e.g. health checks.
+/// Patterns are matched against the URI path; `*` matches any run of
+/// characters, including `/`.
+#[derive(Debug, Default)]
+pub struct PathFilter {
+    patterns: Vec<String>,
+}
+
+impl PathFilter {
+    /// Parse a comma-separated list such as `"/healthz,/static/*"`
+    pub fn parse(list: &str) -> Self {
+        let patterns = list
+            .split(',')
+            .map(str::trim)
+            .filter(|p| !p.is_empty())
+            .map(String::from)
+            .collect();
+        PathFilter { patterns }
+    }
+
+    pub fn is_excluded(&self, path: &str) -> bool {
+        self.patterns.iter().any(|p| glob_match(p.as_bytes(), path.as_bytes()))
+    }
+}
+
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` seen and the text index it was tried at
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, t));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == text[t] {
+            p += 1;
+            t += 1;
+        } else if let Some((sp, st)) = star {
+            // Let the last `*` swallow one more character and retry
+            p = sp + 1;
+            t = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
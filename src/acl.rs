@@ -0,0 +1,396 @@
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+
+use crate::cidr::Cidr;
+
+/// What a matching rule does with a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    /// Let through without the --username check
+    Allow,
+    /// Answered with 403 Forbidden
+    Deny,
+    /// Needs valid proxy credentials
+    RequireAuth,
+}
+
+/// What happens to a request once the ACL and the credential check have
+/// had their say
+#[derive(Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Proceed,
+    Forbidden,
+    AuthRequired,
+}
+
+/// Requests no rule matches get the usual --username check; an allow rule
+/// waives it, so only require-auth rules (and unmatched requests) ask for
+/// credentials
+pub fn outcome(action: Option<Action>, authenticated: impl FnOnce() -> bool) -> Outcome {
+    match action {
+        Some(Action::Deny) => Outcome::Forbidden,
+        Some(Action::Allow) => Outcome::Proceed,
+        Some(Action::RequireAuth) | None => match authenticated() {
+            true => Outcome::Proceed,
+            false => Outcome::AuthRequired,
+        },
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct File {
+    #[serde(default)]
+    rules: Vec<RawRule>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRule {
+    #[serde(rename = "type")]
+    kind: Kind,
+    pattern: String,
+    action: Action,
+    schedule: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Kind {
+    /// Target hostname, exact or `*.example.com`
+    Host,
+    /// Client address range
+    ClientCidr,
+}
+
+enum Matcher {
+    Host(String),
+    /// `*.example.com`, stored as `.example.com`
+    Subdomains(String),
+    Client(Cidr),
+}
+
+/// Days of the week (Monday first) and a UTC time of day range; a range
+/// ending before it starts runs past midnight
+struct Schedule {
+    days: [bool; 7],
+    start: u32,
+    end: u32,
+}
+
+struct Rule {
+    matcher: Matcher,
+    action: Action,
+    schedule: Option<Schedule>,
+}
+
+/// Rules from an --acl-file, checked in order; the first match decides
+pub struct Acl {
+    rules: Vec<Rule>,
+}
+
+impl Acl {
+    pub fn load(path: &Path) -> io::Result<Acl> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    fn parse(text: &str) -> io::Result<Acl> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let file: File = toml::from_str(text).map_err(|e| invalid(e.message().to_string()))?;
+        let mut rules = Vec::new();
+        for (n, raw) in file.rules.into_iter().enumerate() {
+            let matcher = match raw.kind {
+                Kind::Host => {
+                    let host = raw.pattern.trim().to_ascii_lowercase();
+                    match host.strip_prefix('*') {
+                        Some(suffix) if suffix.starts_with('.') => Matcher::Subdomains(suffix.to_string()),
+                        _ => Matcher::Host(host),
+                    }
+                }
+                Kind::ClientCidr => Matcher::Client(
+                    Cidr::parse(raw.pattern.trim())
+                        .ok_or_else(|| invalid(format!("rule {}: invalid CIDR {:?}", n + 1, raw.pattern)))?,
+                ),
+            };
+            let schedule = match &raw.schedule {
+                Some(s) => Some(
+                    Schedule::parse(s).ok_or_else(|| invalid(format!("rule {}: invalid schedule {:?}", n + 1, s)))?,
+                ),
+                None => None,
+            };
+            rules.push(Rule {
+                matcher,
+                action: raw.action,
+                schedule,
+            });
+        }
+        Ok(Acl { rules })
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn requires_auth(&self) -> bool {
+        self.rules.iter().any(|rule| rule.action == Action::RequireAuth)
+    }
+
+    /// The action of the first rule matching the client and target host,
+    /// if any does
+    pub fn evaluate(&self, client: IpAddr, host: Option<&str>) -> Option<Action> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        self.evaluate_at(client, host, now)
+    }
+
+    fn evaluate_at(&self, client: IpAddr, host: Option<&str>, now: u64) -> Option<Action> {
+        let host = host.map(|h| h.trim_end_matches('.').to_ascii_lowercase());
+        self.rules
+            .iter()
+            .find(|rule| {
+                let matched = match &rule.matcher {
+                    Matcher::Host(name) => host.as_deref() == Some(name.as_str()),
+                    Matcher::Subdomains(suffix) => host.as_deref().is_some_and(|h| h.ends_with(suffix.as_str())),
+                    Matcher::Client(range) => range.contains(client),
+                };
+                matched && rule.schedule.as_ref().is_none_or(|s| s.is_active(now))
+            })
+            .map(|rule| rule.action)
+    }
+}
+
+impl Schedule {
+    /// `09:00-17:00`, `Mon-Fri 09:00-17:00` or `Sat,Sun 00:00-24:00`
+    fn parse(s: &str) -> Option<Schedule> {
+        let (days, times) = match s.trim().rsplit_once(char::is_whitespace) {
+            Some((days, times)) => (parse_days(days.trim())?, times),
+            None => ([true; 7], s.trim()),
+        };
+        let (start, end) = times.split_once('-')?;
+        Some(Schedule {
+            days,
+            start: parse_minutes(start)?,
+            end: parse_minutes(end)?,
+        })
+    }
+
+    fn is_active(&self, unix_secs: u64) -> bool {
+        // 1970-01-01 was a Thursday
+        let weekday = ((unix_secs / 86400 + 3) % 7) as usize;
+        let minute = ((unix_secs % 86400) / 60) as u32;
+        if self.start <= self.end {
+            self.days[weekday] && (self.start..self.end).contains(&minute)
+        } else if minute >= self.start {
+            self.days[weekday]
+        } else {
+            // The early part of a window that opened the day before
+            self.days[(weekday + 6) % 7] && minute < self.end
+        }
+    }
+}
+
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+fn parse_days(s: &str) -> Option<[bool; 7]> {
+    let day = |d: &str| DAYS.iter().position(|name| d.trim().eq_ignore_ascii_case(name));
+    let mut days = [false; 7];
+    for part in s.split(',') {
+        match part.split_once('-') {
+            Some((first, last)) => {
+                let (first, last) = (day(first)?, day(last)?);
+                let mut d = first;
+                loop {
+                    days[d] = true;
+                    if d == last {
+                        break;
+                    }
+                    d = (d + 1) % 7;
+                }
+            }
+            None => days[day(part)?] = true,
+        }
+    }
+    Some(days)
+}
+
+/// `HH:MM` as minutes past midnight; `24:00` ends a day
+fn parse_minutes(s: &str) -> Option<u32> {
+    let (h, m) = s.trim().split_once(':')?;
+    let (h, m) = (h.parse::<u32>().ok()?, m.parse::<u32>().ok()?);
+    let minutes = h * 60 + m;
+    (m < 60 && minutes <= 24 * 60).then_some(minutes)
+}
+
+/// The loaded ACL and where it came from, so SIGHUP can reload it
+pub struct AclFile {
+    path: PathBuf,
+    /// Whether the proxy has credentials for require-auth rules to ask for
+    auth_configured: bool,
+    current: RwLock<Arc<Acl>>,
+}
+
+impl AclFile {
+    pub fn load(path: &Path, auth_configured: bool) -> io::Result<AclFile> {
+        Ok(AclFile {
+            path: path.to_path_buf(),
+            auth_configured,
+            current: RwLock::new(Arc::new(Self::read(path, auth_configured)?)),
+        })
+    }
+
+    /// Without credentials every client counts as authenticated, so
+    /// require-auth rules would silently allow everyone
+    fn read(path: &Path, auth_configured: bool) -> io::Result<Acl> {
+        let acl = Acl::load(path)?;
+        if acl.requires_auth() && !auth_configured {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "require-auth rules need --username"));
+        }
+        Ok(acl)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn get(&self) -> Arc<Acl> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Swap in the file's current rules; on error the old ones stay
+    pub fn reload(&self) -> io::Result<usize> {
+        let acl = Self::read(&self.path, self.auth_configured)?;
+        let len = acl.len();
+        *self.current.write().unwrap() = Arc::new(acl);
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::header::HeaderValue;
+
+    use super::*;
+    use crate::auth;
+
+    const RULES: &str = r#"
+        [[rules]]
+        type = "host"
+        pattern = "open.example.com"
+        action = "allow"
+
+        [[rules]]
+        type = "host"
+        pattern = "*.internal.example.com"
+        action = "require-auth"
+
+        [[rules]]
+        type = "client-cidr"
+        pattern = "10.0.0.0/8"
+        action = "deny"
+        schedule = "Mon-Fri 22:00-06:00"
+
+        [[rules]]
+        type = "client-cidr"
+        pattern = "2001:db8::/32"
+        action = "deny"
+    "#;
+
+    /// 2024-01-01 (a Monday) at `hh:mm` UTC
+    fn monday(hh: u64, mm: u64) -> u64 {
+        1_704_067_200 + hh * 3600 + mm * 60
+    }
+
+    fn client(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn allow_skips_credentials_that_require_auth_checks() {
+        let acl = Acl::parse(RULES).unwrap();
+        let noon = monday(12, 0);
+        let ip = client("192.0.2.1");
+        // "alice:wrong" and "alice:secret"
+        for (header, valid) in [("Basic YWxpY2U6d3Jvbmc=", false), ("Basic YWxpY2U6c2VjcmV0", true)] {
+            let header = HeaderValue::from_static(header);
+            let authenticated = || auth::basic_matches(&header, "alice", "secret");
+            let allowed = acl.evaluate_at(ip, Some("open.example.com"), noon);
+            assert_eq!(allowed, Some(Action::Allow));
+            assert_eq!(outcome(allowed, authenticated), Outcome::Proceed);
+            let guarded = acl.evaluate_at(ip, Some("db.internal.example.com"), noon);
+            assert_eq!(guarded, Some(Action::RequireAuth));
+            let expected = if valid { Outcome::Proceed } else { Outcome::AuthRequired };
+            assert_eq!(outcome(guarded, authenticated), expected);
+            assert_eq!(outcome(None, authenticated), expected);
+        }
+        assert_eq!(outcome(Some(Action::Deny), || true), Outcome::Forbidden);
+    }
+
+    #[test]
+    fn host_rules_match_exact_and_subdomains() {
+        let acl = Acl::parse(RULES).unwrap();
+        let at = |host| acl.evaluate_at(client("192.0.2.1"), Some(host), monday(12, 0));
+        assert_eq!(at("OPEN.example.com."), Some(Action::Allow));
+        assert_eq!(at("sub.open.example.com"), None);
+        assert_eq!(at("a.b.internal.example.com"), Some(Action::RequireAuth));
+        assert_eq!(at("internal.example.com"), None);
+        assert_eq!(acl.evaluate_at(client("192.0.2.1"), None, monday(12, 0)), None);
+    }
+
+    #[test]
+    fn cidr_rules_match_clients_and_respect_schedules() {
+        let acl = Acl::parse(RULES).unwrap();
+        let at = |ip, now| acl.evaluate_at(client(ip), Some("other.example.com"), now);
+        assert_eq!(at("10.1.2.3", monday(23, 0)), Some(Action::Deny));
+        assert_eq!(at("::ffff:10.1.2.3", monday(23, 0)), Some(Action::Deny));
+        assert_eq!(at("10.1.2.3", monday(12, 0)), None);
+        assert_eq!(at("11.1.2.3", monday(23, 0)), None);
+        assert_eq!(at("2001:db8::1", monday(12, 0)), Some(Action::Deny));
+        assert_eq!(at("2001:db9::1", monday(12, 0)), None);
+    }
+
+    #[test]
+    fn schedules_parse_days_and_overnight_windows() {
+        let overnight = Schedule::parse("Mon-Fri 22:00-06:00").unwrap();
+        assert!(overnight.is_active(monday(22, 0)));
+        assert!(!overnight.is_active(monday(21, 59)));
+        // Tuesday morning continues Monday night's window
+        assert!(overnight.is_active(monday(24 + 5, 59)));
+        assert!(!overnight.is_active(monday(24 + 6, 0)));
+        // Sunday night isn't a weekday, but Friday night runs into Saturday
+        assert!(!overnight.is_active(monday(24 * 6 + 23, 0)));
+        assert!(overnight.is_active(monday(24 * 5 + 1, 0)));
+
+        let weekend = Schedule::parse("Sat,Sun 00:00-24:00").unwrap();
+        assert!(weekend.is_active(monday(24 * 5 + 12, 0)));
+        assert!(!weekend.is_active(monday(12, 0)));
+        let wrapping = Schedule::parse("Sat-Mon 09:00-17:00").unwrap();
+        assert!(wrapping.is_active(monday(9, 0)));
+        assert!(!wrapping.is_active(monday(24 + 9, 0)));
+        assert!(Schedule::parse("09:00-17:00").unwrap().is_active(monday(16, 59)));
+
+        for bad in ["", "9-17", "Mon-Fri", "Mon-Fry 09:00-17:00", "09:60-17:00", "09:00-24:01"] {
+            assert!(Schedule::parse(bad).is_none(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn invalid_rules_are_rejected() {
+        let rule = |kind: &str, pattern: &str, schedule: &str| {
+            Acl::parse(&format!(
+                "[[rules]]\ntype = \"{}\"\npattern = \"{}\"\naction = \"deny\"\n{}",
+                kind, pattern, schedule
+            ))
+        };
+        assert!(rule("client-cidr", "10.0.0.0/33", "").is_err());
+        assert!(rule("client-cidr", "example.com", "").is_err());
+        assert!(rule("host", "example.com", "schedule = \"Someday 09:00-17:00\"").is_err());
+        assert!(rule("url", "example.com", "").is_err());
+        assert!(rule("host", "example.com", "").is_ok());
+    }
+}
@@ -0,0 +1,61 @@
+use std::collections::HashMap;
+use std::hash::{BuildHasher, RandomState};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hyper::header::HeaderValue;
+
+/// How long a verified Proxy-Authorization value is trusted without decoding it again
+const TTL: Duration = Duration::from_secs(300);
+/// Verified values remembered at once; a client cycling through credentials
+/// can't grow the cache past this
+const MAX_ENTRIES: usize = 1024;
+
+/// Proxy-Authorization values that recently passed the full Basic check,
+/// keyed by their SipHash. Keys are random per process, so collisions can't
+/// be precomputed
+#[derive(Debug, Default)]
+pub struct AuthCache {
+    keys: RandomState,
+    verified: Mutex<HashMap<u64, Instant>>,
+}
+
+impl AuthCache {
+    pub fn contains(&self, header: &[u8]) -> bool {
+        let key = self.keys.hash_one(header);
+        let verified = self.verified.lock().unwrap();
+        verified.get(&key).is_some_and(|&until| Instant::now() < until)
+    }
+
+    /// Remember a header value that just passed verification
+    pub fn insert(&self, header: &[u8]) {
+        let key = self.keys.hash_one(header);
+        let mut verified = self.verified.lock().unwrap();
+        if verified.len() >= MAX_ENTRIES {
+            let now = Instant::now();
+            verified.retain(|_, until| now < *until);
+            if verified.len() >= MAX_ENTRIES {
+                return;
+            }
+        }
+        verified.insert(key, Instant::now() + TTL);
+    }
+
+    pub fn clear(&self) {
+        self.verified.lock().unwrap().clear();
+    }
+}
+
+/// Whether a Proxy-Authorization value is `Basic base64(username:password)`
+pub fn basic_matches(header: &HeaderValue, username: &str, password: &str) -> bool {
+    if let Ok(s) = header.to_str()
+        && let Some(encoded) = s.strip_prefix("Basic ")
+        && let Ok(decoded) = STANDARD.decode(encoded)
+        && let Ok(creds) = std::str::from_utf8(&decoded)
+    {
+        return creds == format!("{}:{}", username, password);
+    }
+    false
+}
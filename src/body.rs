@@ -0,0 +1,319 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use async_compression::tokio::bufread::{BrotliDecoder, GzipDecoder, ZlibDecoder};
+use futures_util::{stream, StreamExt, TryStreamExt};
+use hyper::body::HttpBody;
+use hyper::body::Bytes;
+use hyper::Body;
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncRead;
+use tokio::time::Instant;
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use crate::metrics::METRICS;
+use crate::request_id::RequestId;
+
+/// Stream `body` through unchanged while hashing it, logging the
+/// SHA-256 digest as `field` once the last chunk has passed.
+pub fn tee_sha256(body: Body, req_id: RequestId, field: &'static str) -> Body {
+    // Leave empty bodies alone so hyper still knows they are empty
+    if body.is_end_stream() {
+        log_digest(req_id, field, Sha256::new());
+        return body;
+    }
+
+    let state = (body, Some(Sha256::new()));
+    Body::wrap_stream(stream::unfold(state, move |(mut body, mut hasher)| async move {
+        let h = hasher.as_mut()?;
+        match body.data().await {
+            Some(Ok(chunk)) => {
+                h.update(&chunk);
+                // hyper stops polling once Content-Length is satisfied, so
+                // don't wait for the trailing `None` to log
+                if body.is_end_stream()
+                    && let Some(h) = hasher.take()
+                {
+                    log_digest(req_id, field, h);
+                }
+                Some((Ok(chunk), (body, hasher)))
+            }
+            // Don't log a digest for a body that never completed
+            Some(Err(e)) => Some((Err(e), (body, None))),
+            None => {
+                log_digest(req_id, field, hasher.take()?);
+                None
+            }
+        }
+    }))
+}
+
+/// Stream `body` through unchanged, adding its length to `domain`'s
+/// --per-domain-stats bytes when it ends or the client goes away
+pub fn count_domain_bytes(body: Body, domain: String) -> Body {
+    if body.is_end_stream() {
+        return body;
+    }
+
+    let state = (body, DomainBytes { domain, bytes: 0 });
+    Body::wrap_stream(stream::unfold(state, |(mut body, mut counted)| async move {
+        let chunk = body.data().await?;
+        if let Ok(chunk) = &chunk {
+            counted.bytes += chunk.len() as u64;
+        }
+        Some((chunk, (body, counted)))
+    }))
+}
+
+/// Stream `body` through one chunk per poll, for
+/// --upstream-response-buffering off. Each chunk is handed to hyper the
+/// moment upstream produces it; nothing is coalesced on the way.
+pub fn unbuffered(body: Body) -> Body {
+    if body.is_end_stream() {
+        return body;
+    }
+
+    Body::wrap_stream(stream::unfold(body, |mut body| async move {
+        let chunk = body.data().await?;
+        Some((chunk, body))
+    }))
+}
+
+/// Reports on drop, which also covers bodies abandoned part way
+struct DomainBytes {
+    domain: String,
+    bytes: u64,
+}
+
+impl Drop for DomainBytes {
+    fn drop(&mut self) {
+        METRICS.domain_bytes(&self.domain, self.bytes);
+    }
+}
+
+/// Content-Encodings `decompress` can undo
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Coding {
+    Gzip,
+    Deflate,
+    Brotli,
+}
+
+impl Coding {
+    pub fn parse(s: &str) -> Option<Coding> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => Some(Coding::Gzip),
+            "deflate" => Some(Coding::Deflate),
+            "br" => Some(Coding::Brotli),
+            _ => None,
+        }
+    }
+}
+
+/// Undo `coding` as the body streams
+pub fn decompress(body: Body, coding: Coding) -> Body {
+    let read = StreamReader::new(TryStreamExt::map_err(body, std::io::Error::other));
+    let decoder: Box<dyn AsyncRead + Send + Unpin> = match coding {
+        Coding::Gzip => Box::new(GzipDecoder::new(read)),
+        // HTTP's "deflate" is the zlib format, not a raw deflate stream
+        Coding::Deflate => Box::new(ZlibDecoder::new(read)),
+        Coding::Brotli => Box::new(BrotliDecoder::new(read)),
+    };
+    Body::wrap_stream(ReaderStream::new(decoder))
+}
+
+/// Bytes one request may buffer in memory, shared by every step that
+/// accumulates its request or response body
+#[derive(Debug)]
+pub struct MemoryBudget(AtomicUsize);
+
+impl MemoryBudget {
+    pub fn new(bytes: usize) -> MemoryBudget {
+        MemoryBudget(AtomicUsize::new(bytes))
+    }
+
+    /// Claim `n` bytes, or claim nothing if fewer than that are left
+    fn take(&self, n: usize) -> bool {
+        self.0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| left.checked_sub(n))
+            .is_ok()
+    }
+}
+
+/// Pass `body` through, aborting it once fewer than `min_rate` bytes per
+/// second arrived over a whole `window`, so a trickling upstream can't hold
+/// the connection open indefinitely
+pub fn enforce_min_rate(body: Body, min_rate: u64, window: Duration, req_id: RequestId) -> Body {
+    if body.is_end_stream() {
+        return body;
+    }
+    let required = (min_rate as f64 * window.as_secs_f64()) as u64;
+    let state = (body, Instant::now() + window, 0u64);
+    Body::wrap_stream(stream::unfold(state, move |(mut body, mut deadline, mut received)| async move {
+        loop {
+            match tokio::time::timeout_at(deadline, body.data()).await {
+                Ok(Some(Ok(chunk))) => {
+                    received += chunk.len() as u64;
+                    if Instant::now() >= deadline {
+                        if received < required {
+                            return Some((Err(too_slow(req_id, received, window)), (body, deadline, received)));
+                        }
+                        deadline = Instant::now() + window;
+                        received = 0;
+                    }
+                    return Some((Ok(chunk), (body, deadline, received)));
+                }
+                Ok(Some(Err(e))) => return Some((Err(e.into()), (body, deadline, received))),
+                Ok(None) => return None,
+                // Nothing arrived before the window ended
+                Err(_) if received < required => {
+                    return Some((Err(too_slow(req_id, received, window)), (body, deadline, received)));
+                }
+                Err(_) => {
+                    deadline = Instant::now() + window;
+                    received = 0;
+                }
+            }
+        }
+    }))
+}
+
+fn too_slow(req_id: RequestId, received: u64, window: Duration) -> Box<dyn std::error::Error + Send + Sync> {
+    log!(
+        "[req {}] upstream response too slow: {} bytes in {}s, aborting",
+        req_id,
+        received,
+        window.as_secs_f64()
+    );
+    "upstream response below --response-min-rate".into()
+}
+
+pub enum ReadError {
+    TooLarge,
+    OverBudget,
+    Body(hyper::Error),
+}
+
+/// Buffer a whole body, giving up once it grows past `limit` bytes or the
+/// request's memory budget runs out
+pub async fn read_limited(mut body: Body, limit: usize, budget: &MemoryBudget) -> Result<Bytes, ReadError> {
+    let mut buf = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(ReadError::Body)?;
+        if buf.len() + chunk.len() > limit {
+            return Err(ReadError::TooLarge);
+        }
+        if !budget.take(chunk.len()) {
+            return Err(ReadError::OverBudget);
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(buf.into())
+}
+
+/// A body read up to a size limit
+pub enum Prefix {
+    Complete(Bytes),
+    /// Longer than the limit; still the whole body, ready to forward
+    Partial(Body),
+}
+
+/// Buffer `body` if it fits in `limit` bytes. A larger one is handed back
+/// with what was already read put in front of the rest; running out of the
+/// memory budget first is an error, so the body is never passed on unread.
+pub async fn read_prefix(mut body: Body, limit: usize, budget: &MemoryBudget) -> Result<Prefix, ReadError> {
+    if body.size_hint().lower() > limit as u64 {
+        return Ok(Prefix::Partial(body));
+    }
+    let mut chunks = Vec::new();
+    let mut len = 0;
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(ReadError::Body)?;
+        len += chunk.len();
+        if len > limit {
+            chunks.push(chunk);
+            let read = stream::iter(chunks.into_iter().map(Ok::<_, hyper::Error>));
+            return Ok(Prefix::Partial(Body::wrap_stream(read.chain(body))));
+        }
+        if !budget.take(chunk.len()) {
+            return Err(ReadError::OverBudget);
+        }
+        chunks.push(chunk);
+    }
+    Ok(Prefix::Complete(chunks.concat().into()))
+}
+
+fn log_digest(req_id: RequestId, field: &str, hasher: Sha256) {
+    log!("[req {}] {}: \"{}\"", req_id, field, hex(&hasher.finalize()));
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunked(chunks: &[&'static [u8]]) -> Body {
+        let chunks: Vec<_> = chunks.iter().map(|c| Ok::<_, hyper::Error>(Bytes::from_static(c))).collect();
+        Body::wrap_stream(stream::iter(chunks))
+    }
+
+    #[tokio::test]
+    async fn decompress_undoes_each_coding() {
+        use async_compression::tokio::bufread::{BrotliEncoder, GzipEncoder, ZlibEncoder};
+        use tokio::io::AsyncReadExt;
+
+        let plain = b"hello compressed world\n".repeat(50);
+        for coding in ["gzip", "deflate", "br"] {
+            let mut encoded = Vec::new();
+            let input = &plain[..];
+            match coding {
+                "gzip" => GzipEncoder::new(input).read_to_end(&mut encoded).await,
+                "deflate" => ZlibEncoder::new(input).read_to_end(&mut encoded).await,
+                _ => BrotliEncoder::new(input).read_to_end(&mut encoded).await,
+            }
+            .unwrap();
+            let body = decompress(Body::from(encoded), Coding::parse(coding).unwrap());
+            assert_eq!(hyper::body::to_bytes(body).await.unwrap(), plain, "{}", coding);
+        }
+        assert_eq!(Coding::parse(" X-GZIP "), Some(Coding::Gzip));
+        assert_eq!(Coding::parse("gzip, br"), None);
+        assert_eq!(Coding::parse("compress"), None);
+    }
+
+    #[tokio::test]
+    async fn unbuffered_keeps_chunk_boundaries() {
+        let mut body = unbuffered(chunked(&[b"ab", b"c", b"def"]));
+        let mut seen = Vec::new();
+        while let Some(chunk) = body.data().await {
+            seen.push(chunk.unwrap());
+        }
+        assert_eq!(seen, [&b"ab"[..], b"c", b"def"]);
+        assert!(unbuffered(Body::empty()).is_end_stream());
+    }
+
+    #[tokio::test]
+    async fn read_prefix_tells_size_limit_from_budget() {
+        let budget = MemoryBudget::new(100);
+        match read_prefix(chunked(&[b"abc", b"def"]), 10, &budget).await {
+            Ok(Prefix::Complete(bytes)) => assert_eq!(&bytes[..], b"abcdef"),
+            _ => panic!("expected the whole body"),
+        }
+
+        let budget = MemoryBudget::new(100);
+        match read_prefix(chunked(&[b"abc", b"def"]), 4, &budget).await {
+            Ok(Prefix::Partial(body)) => {
+                assert_eq!(&hyper::body::to_bytes(body).await.unwrap()[..], b"abcdef");
+            }
+            _ => panic!("expected the body back uninspected"),
+        }
+
+        let budget = MemoryBudget::new(4);
+        assert!(matches!(
+            read_prefix(chunked(&[b"abc", b"def"]), 10, &budget).await,
+            Err(ReadError::OverBudget)
+        ));
+    }
+}
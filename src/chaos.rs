@@ -0,0 +1,84 @@
+use std::fs;
+use std::hash::{BuildHasher, RandomState};
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use regex::Regex;
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct File {
+    chaos: Section,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Section {
+    #[serde(default)]
+    latency_ms: u64,
+    #[serde(default)]
+    latency_jitter_ms: u64,
+    #[serde(default)]
+    packet_loss_percent: u8,
+    target_pattern: Option<String>,
+}
+
+/// Fault injection for testing clients against a degraded network
+#[derive(Debug)]
+pub struct Chaos {
+    pub latency: Duration,
+    pub jitter: Duration,
+    /// Share of matching requests answered with 502, 0-100
+    pub packet_loss_percent: u8,
+    /// Only requests whose target matches are affected; all when `None`
+    pub pattern: Option<Regex>,
+}
+
+impl Chaos {
+    /// Read the `[chaos]` section of a TOML file
+    pub fn load(path: &Path) -> io::Result<Chaos> {
+        let text = fs::read_to_string(path)?;
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let File { chaos } = toml::from_str(&text).map_err(|e| invalid(e.message().to_string()))?;
+        if chaos.packet_loss_percent > 100 {
+            return Err(invalid(format!("packet_loss_percent {} is over 100", chaos.packet_loss_percent)));
+        }
+        let pattern = match &chaos.target_pattern {
+            Some(p) => Some(Regex::new(p).map_err(|e| invalid(format!("target_pattern: {}", e)))?),
+            None => None,
+        };
+        Ok(Chaos {
+            latency: Duration::from_millis(chaos.latency_ms),
+            jitter: Duration::from_millis(chaos.latency_jitter_ms),
+            packet_loss_percent: chaos.packet_loss_percent,
+            pattern,
+        })
+    }
+
+    pub fn applies_to(&self, target: &str) -> bool {
+        self.pattern.as_ref().is_none_or(|p| p.is_match(target))
+    }
+
+    /// Sleep for the configured latency plus up to `jitter` extra
+    pub async fn delay(&self) {
+        let jitter = match self.jitter.as_millis() as u64 {
+            0 => 0,
+            max => random() % (max + 1),
+        };
+        let delay = self.latency + Duration::from_millis(jitter);
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    pub fn should_fail(&self) -> bool {
+        random() % 100 < self.packet_loss_percent as u64
+    }
+}
+
+/// Each RandomState gets fresh keys, which is plenty for picking faults
+fn random() -> u64 {
+    RandomState::new().hash_one(())
+}
@@ -0,0 +1,130 @@
+use std::fmt;
+use std::net::SocketAddr;
+
+use hyper::header::HeaderValue;
+
+use crate::Args;
+
+/// One problem with the command line, reported together with all the others
+#[derive(Debug)]
+pub struct ConfigError {
+    field: &'static str,
+    value: String,
+    reason: &'static str,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "--{} {:?}: {}", self.field, self.value, self.reason)
+    }
+}
+
+/// Check the settings clap can't check on its own, collecting every error
+/// so they can all be fixed in one go
+pub fn validate(args: &Args) -> Vec<ConfigError> {
+    let mut errors = Vec::new();
+    let mut fail = |field, value: &dyn fmt::Display, reason| {
+        errors.push(ConfigError {
+            field,
+            value: value.to_string(),
+            reason,
+        })
+    };
+
+    if args.listen.parse::<SocketAddr>().is_err() {
+        fail("listen", &args.listen, "expected an IP:port socket address");
+    }
+    if args.username.is_empty() && !args.password.is_empty() {
+        fail("password", &"<redacted>", "has no effect without --username");
+    }
+    if args.tcp_fastopen && !cfg!(target_os = "linux") {
+        fail("tcp-fastopen", &true, "only supported on Linux");
+    }
+    if let Some(routes) = &args.bind_interface_by_target_prefix
+        && !cfg!(target_os = "linux")
+    {
+        fail("bind-interface-by-target-prefix", routes, "only supported on Linux");
+    }
+    if let Some(dscp) = &args.tunnel_traffic_class
+        && !cfg!(target_os = "linux")
+    {
+        fail("tunnel-traffic-class", &format!("{:?}", dscp), "only supported on Linux");
+    }
+    if let Some(n) = args.max_open_files
+        && !cfg!(target_os = "linux")
+    {
+        fail("max-open-files", &n, "only supported on Linux");
+    }
+    if let Some(origin) = &args.cors_origin
+        && HeaderValue::from_str(origin).is_err()
+    {
+        fail("cors-origin", origin, "not a valid header value");
+    }
+    if let Some(gateway) = &args.push_metrics_to {
+        match gateway.parse::<hyper::Uri>() {
+            Ok(url) if url.scheme_str() == Some("http") && url.authority().is_some() => {}
+            _ => fail("push-metrics-to", gateway, "expected an http://host:port URL"),
+        }
+    }
+    if let Some(server) = &args.syslog_server
+        && !args.syslog
+    {
+        fail("syslog-server", server, "has no effect without --syslog");
+    }
+    if args.connection_limit_soft == Some(0) {
+        fail("connection-limit-soft", &0, "must be at least 1");
+    }
+    if args.connection_limit_by_username == Some(0) {
+        fail("connection-limit-by-username", &0, "must be at least 1");
+    }
+    if let Some(message) = &args.block_response_body
+        && args.block_response_status.is_empty()
+    {
+        fail("block-response-body", message, "has no effect without --block-response-status");
+    }
+    if let Some(salt) = &args.connect_log_redact_salt
+        && crate::log::parse_hex(salt).is_none()
+    {
+        fail("connect-log-redact-salt", &"<redacted>", "expected an even number of hex digits");
+    }
+    if args.prometheus_auth_token.as_deref() == Some("") {
+        fail("prometheus-auth-token", &"", "must not be empty");
+    }
+    if args.request_size_buckets.windows(2).any(|w| w[0] >= w[1]) {
+        fail("request-size-buckets", &format!("{:?}", args.request_size_buckets), "must be in increasing order");
+    }
+    if args.upstream_keepalive_timeout == Some(0) {
+        fail(
+            "upstream-keepalive-timeout",
+            &0,
+            "must be at least 1 second (use --upstream-keepalive-max-idle 0 to turn pooling off)",
+        );
+    }
+    if args.tcp_backlog == 0 {
+        fail("tcp-backlog", &0, "must be at least 1");
+    }
+    if args.tunnel_byte_limit == Some(0) {
+        fail("tunnel-byte-limit", &0, "must be at least 1");
+    }
+    if args.request_ring_buffer_size == Some(0) {
+        fail("request-ring-buffer-size", &0, "must be at least 1");
+    }
+    if args.per_domain_stats == Some(0) {
+        fail("per-domain-stats", &0, "must be at least 1");
+    }
+    if args.max_in_flight_requests == Some(0) {
+        fail("max-in-flight-requests", &0, "must be at least 1");
+    }
+    if args.max_authority_length == 0 {
+        fail("max-authority-length", &0, "must be at least 1");
+    }
+    if args.response_min_rate_window == 0 {
+        fail("response-min-rate-window", &0, "must be at least 1 second");
+    }
+    for target in &args.test_connectivity {
+        if !target.rsplit_once(':').is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok()) {
+            fail("test-connectivity", target, "expected host:port");
+        }
+    }
+    errors
+}
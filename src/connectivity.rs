@@ -0,0 +1,112 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+/// Largest CONNECT reply head we are willing to read
+const MAX_REPLY_HEAD: usize = 8192;
+
+/// Budget for one target, from dialing the proxy to a finished handshake
+const TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Open a CONNECT tunnel to each target through the proxy at `proxy` and
+/// complete a TLS handshake across it; returns whether every target passed
+pub async fn run(proxy: SocketAddr, targets: &[String], auth: Option<&(String, String)>) -> bool {
+    let tls = match tls_connector() {
+        Ok(tls) => tls,
+        Err(e) => {
+            log!("[test] TLS setup failed: {}", e);
+            return false;
+        }
+    };
+    let mut all_ok = true;
+    for target in targets {
+        let started = Instant::now();
+        let result = tokio::time::timeout(TEST_TIMEOUT, check(proxy, target, auth, &tls))
+            .await
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")));
+        match result {
+            Ok(reply) => log!(
+                "[test] {} ok: CONNECT reply {}ms, TLS handshake done {}ms",
+                target,
+                reply.as_millis(),
+                started.elapsed().as_millis()
+            ),
+            Err(e) => {
+                all_ok = false;
+                log!("[test] {} failed after {}ms: {}", target, started.elapsed().as_millis(), e);
+            }
+        }
+    }
+    all_ok
+}
+
+fn tls_connector() -> Result<TlsConnector, tokio_rustls::rustls::Error> {
+    let mut roots = RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// Returns how long the proxy took to answer the CONNECT
+async fn check(
+    proxy: SocketAddr,
+    target: &str,
+    auth: Option<&(String, String)>,
+    tls: &TlsConnector,
+) -> io::Result<Duration> {
+    let host = target.rsplit_once(':').map_or(target, |(host, _)| host);
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let name = ServerName::try_from(host.to_string())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid TLS server name"))?;
+
+    let started = Instant::now();
+    let mut stream = TcpStream::connect(proxy).await?;
+    let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", target, target);
+    if let Some((user, pass)) = auth {
+        let credentials = STANDARD.encode(format!("{}:{}", user, pass));
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", credentials));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    let status = read_reply_status(&mut stream).await?;
+    if status != "200" {
+        return Err(io::Error::other(format!("proxy answered {}", status)));
+    }
+    // The proxy replies before dialing the target, so only a completed TLS
+    // handshake shows that DNS, the TCP connect and the tunnel all worked
+    let reply = started.elapsed();
+    tls.connect(name, stream).await?;
+    Ok(reply)
+}
+
+/// Read the CONNECT reply head byte by byte, so no tunnel data is consumed
+async fn read_reply_status(stream: &mut TcpStream) -> io::Result<String> {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_REPLY_HEAD {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "CONNECT reply too large"));
+        }
+        let mut byte = [0u8];
+        if stream.read(&mut byte).await? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "proxy closed the connection"));
+        }
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let status = head.split_whitespace().nth(1).unwrap_or("");
+    Ok(status.to_string())
+}
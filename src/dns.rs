@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use hickory_resolver::config::{NameServerConfig, Protocol, ResolverConfig, ResolverOpts};
+use hickory_resolver::TokioAsyncResolver;
+use hyper::client::connect::dns::Name;
+use hyper::client::HttpConnector;
+use hyper::service::Service;
+use tokio::net::lookup_host;
+
+use crate::{log, metrics};
+
+/// Hosts whose last answer expiry is remembered for cache hit reporting
+const MAX_TRACKED_ANSWERS: usize = 4096;
+
+/// Connector for forwarded requests, resolving through the same `Resolver`
+/// as CONNECT targets
+pub type Connector = HttpConnector<Resolver>;
+
+/// How upstream hostnames are resolved
+#[derive(Clone)]
+pub struct Resolver {
+    backend: Backend,
+    /// --report-long-dns-resolution
+    report_slow: Option<Duration>,
+    redact: Option<log::Redactor>,
+}
+
+#[derive(Clone)]
+enum Backend {
+    /// getaddrinfo, i.e. /etc/resolv.conf and /etc/hosts
+    System,
+    /// Fixed nameservers from --upstream-dns-servers, with hickory's answer cache
+    Servers(Arc<TokioAsyncResolver>, Arc<Answers>),
+}
+
+/// When the last answer for each host expires. hickory hands out cached
+/// answers with the original expiry, while a fresh answer gets a new one,
+/// so an unchanged expiry means the lookup was served from the cache.
+#[derive(Default)]
+struct Answers(Mutex<HashMap<String, Instant>>);
+
+impl Answers {
+    /// Record `valid_until` for `host` and return whether it was already known
+    fn seen(&self, host: &str, valid_until: Instant) -> bool {
+        let mut answers = self.0.lock().unwrap();
+        if answers.get(host) == Some(&valid_until) {
+            return true;
+        }
+        if !answers.contains_key(host) && answers.len() >= MAX_TRACKED_ANSWERS {
+            let now = Instant::now();
+            answers.retain(|_, expiry| *expiry > now);
+            if answers.len() >= MAX_TRACKED_ANSWERS {
+                return false;
+            }
+        }
+        answers.insert(host.to_string(), valid_until);
+        false
+    }
+}
+
+/// How a lookup went, for --log-connect-resolve-time
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Resolved {
+    pub elapsed: Duration,
+    /// Answered from the --upstream-dns-servers cache; getaddrinfo
+    /// doesn't say, so system lookups never count as hits
+    pub cache_hit: bool,
+}
+
+impl Resolver {
+    pub fn system() -> Resolver {
+        Resolver {
+            backend: Backend::System,
+            report_slow: None,
+            redact: None,
+        }
+    }
+
+    pub fn with_servers(servers: &[SocketAddr], tcp: bool) -> Resolver {
+        let protocol = if tcp { Protocol::Tcp } else { Protocol::Udp };
+        let mut config = ResolverConfig::new();
+        for &server in servers {
+            config.add_name_server(NameServerConfig::new(server, protocol));
+        }
+        Resolver {
+            backend: Backend::Servers(
+                Arc::new(TokioAsyncResolver::tokio(config, ResolverOpts::default())),
+                Arc::default(),
+            ),
+            report_slow: None,
+            redact: None,
+        }
+    }
+
+    /// Log lookups that take longer than `threshold`
+    pub fn report_slow(mut self, threshold: Option<Duration>) -> Resolver {
+        self.report_slow = threshold;
+        self
+    }
+
+    /// Hash hostnames in the resolver's own log lines
+    pub fn redact_logs(mut self, redact: Option<log::Redactor>) -> Resolver {
+        self.redact = redact;
+        self
+    }
+
+    /// Resolve `host` (an IP literal is returned as is) to socket addresses on `port`
+    pub async fn lookup(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        Ok(self.lookup_traced(host, port).await?.0)
+    }
+
+    /// `lookup`, also saying how long it took and whether it was cached
+    pub async fn lookup_traced(&self, host: &str, port: u16) -> io::Result<(Vec<SocketAddr>, Resolved)> {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok((vec![SocketAddr::new(ip, port)], Resolved::default()));
+        }
+        let started = Instant::now();
+        let mut cache_hit = false;
+        let addrs = match &self.backend {
+            Backend::System => lookup_host((host, port)).await.map(Iterator::collect),
+            Backend::Servers(resolver, answers) => match resolver.lookup_ip(host).await {
+                Ok(ips) => {
+                    cache_hit = answers.seen(host, ips.valid_until());
+                    Ok(ips.iter().map(|ip| SocketAddr::new(ip, port)).collect())
+                }
+                Err(e) => Err(io::Error::other(e)),
+            },
+        };
+        let elapsed = started.elapsed();
+        metrics::METRICS.dns_resolution_ms.observe(elapsed.as_millis() as u64);
+        if let Some(threshold) = self.report_slow
+            && elapsed > threshold
+        {
+            let domain = self.redact.as_ref().map_or_else(|| host.to_string(), |r| r.host(host));
+            log!("[dns] slow_dns_resolution domain={} ms={}", domain, elapsed.as_millis());
+        }
+        Ok((addrs?, Resolved { elapsed, cache_hit }))
+    }
+}
+
+impl Service<Name> for Resolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let resolver = self.clone();
+        // HttpConnector fills in the port itself
+        Box::pin(async move { Ok(resolver.lookup(name.as_str(), 0).await?.into_iter()) })
+    }
+}
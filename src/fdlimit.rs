@@ -0,0 +1,69 @@
+use std::fs;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// Share of the descriptor limit in use that gets a warning logged
+const WARN_PERCENT: u64 = 80;
+/// Share of the descriptor limit in use above which new connections get 503
+const REJECT_PERCENT: u64 = 95;
+
+/// Set by the monitor while descriptor usage is above REJECT_PERCENT
+static NEAR_LIMIT: AtomicBool = AtomicBool::new(false);
+
+/// Raise or lower the RLIMIT_NOFILE soft limit to `n`, capped at the hard
+/// limit; returns the limit now in effect
+pub fn set_soft_limit(n: u64) -> io::Result<u64> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: `limit` is a valid, initialized `libc::rlimit` that outlives
+    // the call, and getrlimit only writes within it
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    limit.rlim_cur = n.min(limit.rlim_max);
+    // SAFETY: `limit` is a valid, initialized `libc::rlimit` that outlives
+    // the call, and setrlimit only reads it
+    if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(limit.rlim_cur)
+}
+
+fn open_fds() -> io::Result<u64> {
+    Ok(fs::read_dir("/proc/self/fd")?.count() as u64)
+}
+
+pub fn near_limit() -> bool {
+    NEAR_LIMIT.load(Ordering::Relaxed)
+}
+
+/// Check descriptor usage against `limit` every second
+pub fn spawn_monitor(limit: u64) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        let mut warned = false;
+        loop {
+            interval.tick().await;
+            let Ok(open) = open_fds() else {
+                continue;
+            };
+            let percent = open * 100 / limit.max(1);
+            // Log on crossing the threshold, not on every tick above it
+            if percent >= WARN_PERCENT && !warned {
+                log!("[fd] high descriptor usage: {} of {} in use ({}%)", open, limit, percent);
+            }
+            warned = percent >= WARN_PERCENT;
+            let rejecting = percent >= REJECT_PERCENT;
+            if NEAR_LIMIT.swap(rejecting, Ordering::Relaxed) != rejecting {
+                if rejecting {
+                    log!("[fd] {}% of file descriptors in use, rejecting new connections", percent);
+                } else {
+                    log!("[fd] descriptor usage back to {}%, accepting connections", percent);
+                }
+            }
+        }
+    });
+}
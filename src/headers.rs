@@ -0,0 +1,276 @@
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, CONTENT_TYPE, HOST, PROXY_AUTHORIZATION};
+use hyper::http::uri::{Authority, Uri};
+use hyper::Request;
+use regex::Regex;
+
+/// Headers removed by --strip-tracking-headers unless --tracking-headers says otherwise
+pub const DEFAULT_TRACKING_HEADERS: &str = "DNT,Sec-CH-UA,Sec-CH-UA-Platform,Sec-CH-UA-Mobile,\
+Sec-CH-UA-Arch,Sec-CH-UA-Bitness,Sec-CH-UA-Model,Sec-CH-UA-Platform-Version,Sec-CH-UA-Full-Version-List,\
+Device-Memory,Sec-CH-Device-Memory,DPR,Sec-CH-DPR,Viewport-Width,Sec-CH-Viewport-Width,Width,Downlink,ECT,RTT";
+
+pub const PERMISSIONS_POLICY: HeaderName = HeaderName::from_static("permissions-policy");
+/// The header Permissions-Policy replaced; sites still sending it keep theirs
+pub const FEATURE_POLICY: HeaderName = HeaderName::from_static("feature-policy");
+
+/// W3C Baggage and the trace context header it travels with
+pub const BAGGAGE: HeaderName = HeaderName::from_static("baggage");
+pub const TRACEPARENT: HeaderName = HeaderName::from_static("traceparent");
+
+/// The `key=value` members of a Baggage header, values percent-decoded and
+/// member properties (`;...`) dropped. Malformed members are skipped.
+pub fn baggage_members(value: &str) -> Vec<(String, String)> {
+    value
+        .split(',')
+        .filter_map(|member| {
+            let member = member.split(';').next()?;
+            let (key, value) = member.split_once('=')?;
+            let key = key.trim();
+            (!key.is_empty()).then(|| (key.to_string(), percent_decode(value.trim())))
+        })
+        .collect()
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|h| u8::from_str_radix(std::str::from_utf8(h).ok()?, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                out.push(byte);
+                i += 3;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Whether the Content-Type's media type is one of `types`, where `text/*`
+/// stands for every text type
+pub fn content_type_in(headers: &HeaderMap, types: &[String]) -> bool {
+    let Some(mime) = headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .map(|mime| mime.trim().to_ascii_lowercase())
+    else {
+        return false;
+    };
+    types.iter().any(|t| match t.strip_suffix("/*") {
+        Some(top) => mime.split_once('/').is_some_and(|(ty, _)| ty.eq_ignore_ascii_case(top)),
+        None => mime.eq_ignore_ascii_case(t),
+    })
+}
+
+/// Whether the Content-Type is text/html, whatever its parameters
+/// Whether an Accept-Encoding value lists `coding` (or `*`) with a
+/// non-zero q. `identity` is always acceptable unless refused outright;
+/// other codings need to be named, since a client that sends no header at
+/// all is treated as one that can't decode anything.
+pub fn accepts_coding(accept: Option<&HeaderValue>, coding: &str) -> bool {
+    let mut listed = None;
+    let mut wildcard = None;
+    for item in accept.and_then(|v| v.to_str().ok()).unwrap_or("").split(',') {
+        let mut params = item.split(';');
+        let name = params.next().unwrap_or("").trim();
+        // `q=0` refuses the coding; anything else (or no q) accepts it
+        let accepted = params
+            .filter_map(|p| p.trim().strip_prefix("q=").or_else(|| p.trim().strip_prefix("Q=")))
+            .all(|q| q.trim().parse::<f32>().map_or(true, |q| q > 0.0));
+        if name.eq_ignore_ascii_case(coding) {
+            listed = Some(accepted);
+        } else if name == "*" {
+            wildcard = Some(accepted);
+        }
+    }
+    listed.or(wildcard).unwrap_or(coding.eq_ignore_ascii_case("identity"))
+}
+
+pub fn is_html(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .is_some_and(|mime| mime.trim().eq_ignore_ascii_case("text/html"))
+}
+
+/// Compile a --strip-private-headers pattern to match whole header names,
+/// ignoring case like header names themselves do
+pub fn parse_name_pattern(s: &str) -> Result<Regex, String> {
+    Regex::new(&format!("(?i)^(?:{})$", s)).map_err(|e| e.to_string())
+}
+
+/// Remove every header whose name matches one of `patterns`
+pub fn strip_matching(headers: &mut HeaderMap, patterns: &[Regex]) {
+    let doomed: Vec<_> = headers
+        .keys()
+        .filter(|name| patterns.iter().any(|p| p.is_match(name.as_str())))
+        .cloned()
+        .collect();
+    for name in doomed {
+        headers.remove(name);
+    }
+}
+
+/// Remove what must not reach the upstream from a forwarded request:
+/// --strip-private-headers matches, --strip-tracking-headers names and,
+/// with `strip_proxy_auth`, Proxy-Authorization. Proxy credentials are meant
+/// for us; hyper's client forwards every header it is given, hop-by-hop or not
+pub fn strip_outbound(headers: &mut HeaderMap, private: &[Regex], tracking: &[HeaderName], strip_proxy_auth: bool) {
+    strip_matching(headers, private);
+    for name in tracking {
+        headers.remove(name);
+    }
+    if strip_proxy_auth {
+        headers.remove(PROXY_AUTHORIZATION);
+    }
+}
+
+/// Render the `names` headers present in `headers` as `name="value"` pairs
+/// for a log line, cutting values at `max_len` characters; credentials are
+/// replaced unless `redact_auth` is off
+pub fn format_selected(headers: &HeaderMap, names: &[HeaderName], redact_auth: bool, max_len: usize) -> String {
+    let mut out = Vec::new();
+    for name in names {
+        let values: Vec<_> = headers
+            .get_all(name)
+            .iter()
+            .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned())
+            .collect();
+        if values.is_empty() {
+            continue;
+        }
+        let value = if redact_auth && (name == AUTHORIZATION || name == PROXY_AUTHORIZATION) {
+            "<redacted>".to_string()
+        } else {
+            truncate(values.join(", "), max_len)
+        };
+        out.push(format!("{}={:?}", name, value));
+    }
+    out.join(" ")
+}
+
+fn truncate(mut value: String, max_len: usize) -> String {
+    if let Some((i, _)) = value.char_indices().nth(max_len) {
+        value.truncate(i);
+        value.push_str("...");
+    }
+    value
+}
+
+/// Lowercase the Host header and URI authority and drop trailing dots from
+/// the hostname, so `EXAMPLE.COM.` and `example.com` route the same way
+pub fn normalize_host<B>(req: &mut Request<B>) {
+    if let Some(host) = req.headers().get(HOST).and_then(|h| h.to_str().ok()) {
+        let normalized = normalize_authority(host);
+        if normalized != host
+            && let Ok(value) = HeaderValue::from_str(&normalized)
+        {
+            req.headers_mut().insert(HOST, value);
+        }
+    }
+    if let Some(authority) = req.uri().authority() {
+        let normalized = normalize_authority(authority.as_str());
+        if normalized != authority.as_str()
+            && let Ok(authority) = normalized.parse::<Authority>()
+        {
+            let mut parts = std::mem::take(req.uri_mut()).into_parts();
+            parts.authority = Some(authority);
+            *req.uri_mut() = Uri::from_parts(parts).expect("authority replaced in an absolute URI");
+        }
+    }
+}
+
+fn normalize_authority(authority: &str) -> String {
+    let (userinfo, hostport) = match authority.rsplit_once('@') {
+        Some((userinfo, hostport)) => (Some(userinfo), hostport),
+        None => (None, authority),
+    };
+    // Bracketed IPv6 literals contain colons of their own
+    let split = if hostport.starts_with('[') {
+        hostport.find("]:").map(|i| i + 1)
+    } else {
+        hostport.rfind(':')
+    };
+    let (host, port) = match split {
+        Some(i) => hostport.split_at(i),
+        None => (hostport, ""),
+    };
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    match userinfo {
+        Some(userinfo) => format!("{}@{}{}", userinfo, host, port),
+        None => format!("{}{}", host, port),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_coding_reads_names_wildcards_and_q() {
+        let accepts = |value: Option<&'static str>, coding| accepts_coding(value.map(HeaderValue::from_static).as_ref(), coding);
+        assert!(accepts(Some("gzip, deflate"), "gzip"));
+        assert!(accepts(Some("GZIP;q=0.5"), "gzip"));
+        assert!(!accepts(Some("gzip;q=0"), "gzip"));
+        assert!(!accepts(Some("gzip, deflate"), "br"));
+        assert!(accepts(Some("*"), "br"));
+        assert!(!accepts(Some("*, br;q=0"), "br"));
+        assert!(!accepts(None, "gzip"));
+        assert!(!accepts(Some(""), "gzip"));
+        assert!(accepts(None, "identity"));
+        assert!(!accepts(Some("identity;q=0"), "identity"));
+    }
+
+    #[test]
+    fn strip_matching_removes_only_matching_names() {
+        let mut headers = HeaderMap::new();
+        for i in 0..10 {
+            headers.insert(HeaderName::try_from(format!("x-internal-{}", i)).unwrap(), HeaderValue::from_static("secret"));
+        }
+        for i in 0..40 {
+            headers.insert(HeaderName::try_from(format!("x-keep-{}", i)).unwrap(), HeaderValue::from_static("public"));
+        }
+        assert_eq!(headers.len(), 50);
+
+        strip_matching(&mut headers, &[parse_name_pattern("X-Internal-.*").unwrap()]);
+
+        assert_eq!(headers.len(), 40);
+        assert!(headers.keys().all(|name| name.as_str().starts_with("x-keep-")));
+        for i in 0..40 {
+            assert_eq!(headers[format!("x-keep-{}", i).as_str()], "public");
+        }
+    }
+
+    #[test]
+    fn normalize_authority_lowercases_and_drops_trailing_dots() {
+        assert_eq!(normalize_authority("EXAMPLE.COM"), "example.com");
+        assert_eq!(normalize_authority("Example.Com.:8080"), "example.com:8080");
+        assert_eq!(normalize_authority("example.com.."), "example.com");
+        assert_eq!(normalize_authority("[2001:DB8::1]:443"), "[2001:db8::1]:443");
+        assert_eq!(normalize_authority("[2001:DB8::1]"), "[2001:db8::1]");
+        // Userinfo is case-sensitive and kept as is
+        assert_eq!(normalize_authority("User:PW@Example.COM.:80"), "User:PW@example.com:80");
+    }
+
+    #[test]
+    fn normalize_host_rewrites_header_and_uri() {
+        let mut req = Request::builder()
+            .uri("http://WWW.Example.COM.:8080/Path")
+            .header(HOST, "WWW.Example.COM.:8080")
+            .body(())
+            .unwrap();
+        normalize_host(&mut req);
+        assert_eq!(req.headers()[HOST], "www.example.com:8080");
+        assert_eq!(req.uri(), "http://www.example.com:8080/Path");
+
+        let mut req = Request::builder().uri("/").header(HOST, "[FE80::1]").body(()).unwrap();
+        normalize_host(&mut req);
+        assert_eq!(req.headers()[HOST], "[fe80::1]");
+    }
+}
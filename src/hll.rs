@@ -0,0 +1,70 @@
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Bits of the hash that pick a register: 4096 registers, about 1.6% error
+const PRECISION: u32 = 12;
+const REGISTERS: usize = 1 << PRECISION;
+
+/// HyperLogLog estimate of how many distinct values were inserted, in a
+/// fixed 4 KiB however many there are
+pub struct UniqueCounter {
+    registers: [AtomicU8; REGISTERS],
+}
+
+impl UniqueCounter {
+    pub fn new() -> UniqueCounter {
+        UniqueCounter {
+            registers: [const { AtomicU8::new(0) }; REGISTERS],
+        }
+    }
+
+    pub fn insert(&self, value: &str) {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        let hash = hasher.finish();
+        let register = (hash >> (64 - PRECISION)) as usize;
+        // Position of the first set bit in what's left of the hash
+        let rank = ((hash << PRECISION).leading_zeros().min(64 - PRECISION) + 1) as u8;
+        self.registers[register].fetch_max(rank, Ordering::Relaxed);
+    }
+
+    pub fn estimate(&self) -> u64 {
+        let m = REGISTERS as f64;
+        let mut sum = 0.0;
+        let mut empty = 0;
+        for register in &self.registers {
+            let rank = register.load(Ordering::Relaxed);
+            sum += 2f64.powi(-i32::from(rank));
+            if rank == 0 {
+                empty += 1;
+            }
+        }
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let raw = alpha * m * m / sum;
+        // Small cardinalities are counted more accurately from empty registers
+        let estimate = if raw <= 2.5 * m && empty > 0 {
+            m * (m / f64::from(empty)).ln()
+        } else {
+            raw
+        };
+        estimate.round() as u64
+    }
+
+    pub fn clear(&self) {
+        for register in &self.registers {
+            register.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Clear the counter at the top of every hour (UTC)
+    pub fn spawn_hourly_reset(&'static self) {
+        tokio::spawn(async move {
+            loop {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+                tokio::time::sleep(Duration::from_secs(3600 - now.as_secs() % 3600)).await;
+                self.clear();
+            }
+        });
+    }
+}
@@ -0,0 +1,65 @@
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::process::Command;
+
+/// NAT rules redirecting outbound traffic on some ports to the proxy.
+/// Traffic from the proxy's own uid is excluded so upstream connections
+/// don't loop back into it. The rules are deleted again on drop.
+pub struct RedirectRules {
+    rules: Vec<Vec<String>>,
+    uid: u32,
+}
+
+impl RedirectRules {
+    /// Append one `OUTPUT` REDIRECT rule per port; on failure any rules
+    /// already added are removed again
+    pub fn install(ports: &[u16], to_port: u16) -> io::Result<Self> {
+        let uid = std::fs::metadata("/proc/self")?.uid();
+        let mut installed = RedirectRules { rules: Vec::new(), uid };
+        for port in ports {
+            let rule: Vec<String> = [
+                "OUTPUT", "-p", "tcp", "--dport", &port.to_string(),
+                "-m", "owner", "!", "--uid-owner", &uid.to_string(),
+                "-j", "REDIRECT", "--to-ports", &to_port.to_string(),
+            ]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+            // Dropping `installed` on the way out removes what was added so far
+            iptables("-A", &rule)?;
+            installed.rules.push(rule);
+        }
+        Ok(installed)
+    }
+
+    /// The uid whose traffic is left alone. An app running under it, as
+    /// happens when a sidecar shares the pod's security context, bypasses
+    /// the proxy.
+    pub fn excluded_uid(&self) -> u32 {
+        self.uid
+    }
+}
+
+impl Drop for RedirectRules {
+    /// Delete the rules in reverse order, logging (not failing on) errors
+    fn drop(&mut self) {
+        for rule in self.rules.iter().rev() {
+            if let Err(e) = iptables("-D", rule) {
+                log!("[iptables] failed to remove rule {}: {}", rule.join(" "), e);
+            }
+        }
+    }
+}
+
+fn iptables(op: &str, rule: &[String]) -> io::Result<()> {
+    let status = Command::new("iptables")
+        .args(["-t", "nat", op])
+        .args(rule)
+        .status()
+        .map_err(|e| io::Error::new(e.kind(), format!("failed to run iptables: {}", e)))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!("iptables {} exited with {}", op, status)))
+    }
+}
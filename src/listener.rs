@@ -0,0 +1,180 @@
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use hyper::server::accept::Accept;
+use hyper::server::conn::{AddrIncoming, AddrStream};
+use tokio::net::TcpSocket;
+use tokio::sync::Semaphore;
+use tokio::time::Sleep;
+
+use crate::cidr::BanList;
+
+/// How often a paused accept loop re-checks in-flight capacity
+const BACKPRESSURE_RECHECK: Duration = Duration::from_millis(10);
+/// Share of --tcp-backlog the accept queue may fill before a warning is logged
+#[cfg(target_os = "linux")]
+const ACCEPT_QUEUE_WARN_PERCENT: u64 = 80;
+
+/// Bind the proxy socket with a listen backlog of `backlog`
+pub fn bind(addr: SocketAddr, backlog: u32) -> io::Result<AddrIncoming> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    // std's TcpListener::bind sets this too, so restarts don't hit TIME_WAIT
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    let listener = socket.listen(backlog)?;
+    AddrIncoming::from_listener(listener).map_err(io::Error::other)
+}
+
+/// Connections waiting to be accepted on the listening socket(s) bound to
+/// `port`: for sockets in LISTEN state the kernel reports the accept queue
+/// length as rx_queue
+#[cfg(target_os = "linux")]
+fn accept_queue_depth(port: u16) -> Option<u64> {
+    let mut depth = None;
+    for table in ["/proc/net/tcp", "/proc/net/tcp6"] {
+        let Ok(contents) = std::fs::read_to_string(table) else {
+            continue;
+        };
+        for line in contents.lines().skip(1) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (Some(local), Some(&"0A"), Some(queues)) = (fields.get(1), fields.get(3), fields.get(4)) else {
+                continue;
+            };
+            let local_port = local.rsplit_once(':').and_then(|(_, p)| u16::from_str_radix(p, 16).ok());
+            let rx = queues.split_once(':').and_then(|(_, rx)| u64::from_str_radix(rx, 16).ok());
+            if local_port == Some(port)
+                && let Some(rx) = rx
+            {
+                *depth.get_or_insert(0) += rx;
+            }
+        }
+    }
+    depth
+}
+
+/// Publish the accept queue depth every second and warn when it passes
+/// ACCEPT_QUEUE_WARN_PERCENT of `backlog`
+#[cfg(target_os = "linux")]
+pub fn spawn_accept_queue_monitor(port: u16, backlog: u32) {
+    use std::sync::atomic::Ordering;
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        let mut warned = false;
+        loop {
+            interval.tick().await;
+            let Some(depth) = accept_queue_depth(port) else {
+                continue;
+            };
+            crate::metrics::METRICS.accept_queue_depth.store(depth, Ordering::Relaxed);
+            let high = depth * 100 > u64::from(backlog) * ACCEPT_QUEUE_WARN_PERCENT;
+            // Log on crossing the threshold, not on every tick above it
+            if high && !warned {
+                log!("[conn] accept_queue_high: {} of {} backlog slots in use", depth, backlog);
+            }
+            warned = high;
+        }
+    });
+}
+
+/// The proxy's listening socket, filtering connections before hyper sees them
+pub struct Incoming {
+    inner: AddrIncoming,
+    ban_list: Option<BanList>,
+    backpressure: Option<Backpressure>,
+    conn_log: bool,
+}
+
+/// Stops accepting while fewer than 10% of the in-flight request slots are
+/// free, and starts again once 20% are
+pub struct Backpressure {
+    in_flight: Arc<Semaphore>,
+    pause_below: usize,
+    resume_at: usize,
+    paused: Option<Pin<Box<Sleep>>>,
+}
+
+impl Backpressure {
+    pub fn new(in_flight: Arc<Semaphore>, capacity: usize) -> Backpressure {
+        Backpressure {
+            in_flight,
+            pause_below: capacity.div_ceil(10),
+            resume_at: capacity.div_ceil(5),
+            paused: None,
+        }
+    }
+
+    /// Ready once accepting may go on
+    fn poll_capacity(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        let free = self.in_flight.available_permits();
+        match &mut self.paused {
+            None if free >= self.pause_below => return Poll::Ready(()),
+            None => log!("[conn] backpressure_active: {} in-flight slots free, pausing accept", free),
+            Some(_) if free >= self.resume_at => {
+                log!("[conn] backpressure released: {} in-flight slots free", free);
+                self.paused = None;
+                return Poll::Ready(());
+            }
+            Some(_) => {}
+        }
+        // Permits coming back don't wake us, so check again on a short timer
+        let sleep = self.paused.insert(Box::pin(tokio::time::sleep(BACKPRESSURE_RECHECK)));
+        let _ = sleep.as_mut().poll(cx);
+        Poll::Pending
+    }
+}
+
+impl Incoming {
+    pub fn new(
+        inner: AddrIncoming,
+        ban_list: Option<BanList>,
+        backpressure: Option<Backpressure>,
+        conn_log: bool,
+    ) -> Incoming {
+        Incoming {
+            inner,
+            ban_list,
+            backpressure,
+            conn_log,
+        }
+    }
+}
+
+impl Accept for Incoming {
+    type Conn = AddrStream;
+    type Error = io::Error;
+
+    fn poll_accept(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<io::Result<AddrStream>>> {
+        loop {
+            if let Some(backpressure) = &mut self.backpressure
+                && backpressure.poll_capacity(cx).is_pending()
+            {
+                return Poll::Pending;
+            }
+            let conn = match Pin::new(&mut self.inner).poll_accept(cx) {
+                Poll::Ready(Some(Ok(conn))) => conn,
+                other => return other,
+            };
+            let remote_addr = conn.remote_addr();
+            match &self.ban_list {
+                // Dropping the stream closes it without reading a byte
+                Some(bans) if bans.is_banned(remote_addr.ip()) => {
+                    if self.conn_log {
+                        log!("[conn] dropped banned client {}", remote_addr);
+                    }
+                }
+                _ => return Poll::Ready(Some(Ok(conn))),
+            }
+        }
+    }
+}
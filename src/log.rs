@@ -0,0 +1,177 @@
+use std::io;
+use std::net::UdpSocket;
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::sync::OnceLock;
+
+use clap::ValueEnum;
+use sha2::{Digest, Sha256};
+
+pub mod clf;
+
+/// Static ` key=value` suffix appended to every log line
+static TAGS: OnceLock<String> = OnceLock::new();
+
+/// Optional syslog destination receiving a copy of every log line
+static SYSLOG: OnceLock<Syslog> = OnceLock::new();
+
+/// Print a log line to stderr (and syslog with `--syslog`), followed by
+/// any `--tag` fields
+macro_rules! log {
+    ($($arg:tt)*) => {
+        $crate::log::emit(&format!("{}{}", format_args!($($arg)*), $crate::log::tags()))
+    };
+}
+
+pub fn emit(line: &str) {
+    eprintln!("{}", line);
+    if let Some(syslog) = SYSLOG.get() {
+        syslog.send(line);
+    }
+}
+
+pub fn set_tags(tags: &[(String, String)]) {
+    let suffix = tags.iter().map(|(k, v)| format!(" {}={}", k, v)).collect();
+    let _ = TAGS.set(suffix);
+}
+
+pub fn tags() -> &'static str {
+    TAGS.get().map(String::as_str).unwrap_or("")
+}
+
+/// Parse a `key=value` tag argument
+pub fn parse_tag(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((k, v)) if !k.is_empty() => Ok((k.to_string(), v.to_string())),
+        _ => Err(format!("expected key=value, got '{}'", s)),
+    }
+}
+
+/// Replaces CONNECT target hostnames in logs with the first 8 hex digits
+/// of SHA-256(salt || hostname); the port is kept
+#[derive(Clone, Debug)]
+pub struct Redactor {
+    salt: Vec<u8>,
+}
+
+impl Redactor {
+    pub fn new(salt: Vec<u8>) -> Redactor {
+        Redactor { salt }
+    }
+
+    /// Redact the host of `host:port` (or a bare host)
+    pub fn authority(&self, authority: &str) -> String {
+        match authority.rsplit_once(':') {
+            Some((host, port)) if !host.ends_with(':') && port.bytes().all(|b| b.is_ascii_digit()) => {
+                format!("{}:{}", self.host(host), port)
+            }
+            _ => self.host(authority),
+        }
+    }
+
+    pub fn host(&self, host: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(&self.salt);
+        hasher.update(host.to_ascii_lowercase());
+        hasher.finalize()[..4].iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+/// Decode a --connect-log-redact-salt
+pub fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
+
+/// How completed requests are logged
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// The proxy's own `[req ...]` lines, with `--debug`
+    Text,
+    /// One Common Log Format line per request
+    Clf,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum Facility {
+    User,
+    Daemon,
+    Local0,
+    Local1,
+    Local2,
+    Local3,
+    Local4,
+    Local5,
+    Local6,
+    Local7,
+}
+
+impl Facility {
+    fn code(self) -> u8 {
+        match self {
+            Facility::User => 1,
+            Facility::Daemon => 3,
+            Facility::Local0 => 16,
+            Facility::Local1 => 17,
+            Facility::Local2 => 18,
+            Facility::Local3 => 19,
+            Facility::Local4 => 20,
+            Facility::Local5 => 21,
+            Facility::Local6 => 22,
+            Facility::Local7 => 23,
+        }
+    }
+}
+
+enum Transport {
+    #[cfg(unix)]
+    Unix(UnixDatagram),
+    Udp(UdpSocket),
+}
+
+struct Syslog {
+    transport: Transport,
+    /// `<PRI>dshp[pid]: ` prefix; the daemon adds timestamp and hostname
+    prefix: String,
+}
+
+impl Syslog {
+    fn send(&self, line: &str) {
+        let msg = format!("{}{}", self.prefix, line);
+        // Dropping lines is better than blocking or failing the proxy
+        let _ = match &self.transport {
+            #[cfg(unix)]
+            Transport::Unix(sock) => sock.send(msg.as_bytes()),
+            Transport::Udp(sock) => sock.send(msg.as_bytes()),
+        };
+    }
+}
+
+/// Start copying log lines to syslog, over UDP when `server` (host:port)
+/// is given and to the local `/dev/log` socket otherwise
+pub fn init_syslog(facility: Facility, server: Option<&str>) -> io::Result<()> {
+    let transport = match server {
+        Some(addr) => {
+            let sock = UdpSocket::bind(("0.0.0.0", 0))?;
+            sock.connect(addr)?;
+            Transport::Udp(sock)
+        }
+        #[cfg(unix)]
+        None => {
+            let sock = UnixDatagram::unbound()?;
+            sock.connect("/dev/log")?;
+            Transport::Unix(sock)
+        }
+        #[cfg(not(unix))]
+        None => {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "--syslog needs --syslog-server here"));
+        }
+    };
+    // Severity is always "informational" (6)
+    let pri = facility.code() * 8 + 6;
+    let prefix = format!("<{}>dshp[{}]: ", pri, std::process::id());
+    let _ = SYSLOG.set(Syslog { transport, prefix });
+    Ok(())
+}
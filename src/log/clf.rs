@@ -0,0 +1,64 @@
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hyper::{Method, Version};
+
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// One request in Common Log Format
+pub struct Entry<'a> {
+    pub remote: IpAddr,
+    pub user: Option<&'a str>,
+    pub time: SystemTime,
+    pub method: &'a Method,
+    pub uri: &'a str,
+    pub version: Version,
+    pub status: u16,
+    /// Response body size, when known up front
+    pub bytes: Option<u64>,
+}
+
+/// `remote - user [timestamp] "METHOD URI HTTP/version" status bytes`
+pub fn format(entry: &Entry) -> String {
+    format!(
+        "{} - {} [{}] \"{} {} {:?}\" {} {}",
+        entry.remote,
+        entry.user.unwrap_or("-"),
+        timestamp(entry.time),
+        entry.method,
+        entry.uri,
+        entry.version,
+        entry.status,
+        entry.bytes.filter(|&b| b > 0).map_or("-".to_string(), |b| b.to_string())
+    )
+}
+
+/// `10/Oct/2000:13:55:36 +0000`, always in UTC
+fn timestamp(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let rem = secs % 86400;
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60
+    )
+}
+
+/// Days since 1970-01-01 to (year, month, day), per Howard Hinnant's algorithm
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
@@ -0,0 +1,577 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use hyper::header::{HeaderValue, AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE};
+use hyper::client::Client;
+use hyper::{Body, Method, Request, Response, Server, StatusCode, Uri, Version};
+use serde_json::json;
+
+use crate::dns::Connector;
+use crate::hll::UniqueCounter;
+use crate::recent::RecentRequests;
+
+/// Process-wide metrics registry
+pub static METRICS: Metrics = Metrics {
+    requests_per_session: Histogram::new([1, 2, 5, 10, 20, 50, 100]),
+    max_requests_per_session: AtomicU64::new(0),
+    connection_queue_depth: AtomicU64::new(0),
+    connection_queue_timeouts: AtomicU64::new(0),
+    connection_queue_full: AtomicU64::new(0),
+    accept_queue_depth: AtomicU64::new(0),
+    upstream_latency: Mutex::new(BTreeMap::new()),
+    domains: Mutex::new(BTreeMap::new()),
+    http_versions: [const { AtomicU64::new(0) }; HTTP_VERSIONS.len()],
+    dns_resolution_ms: Histogram::new([1, 5, 10, 50, 100, 500, 1000]),
+    request_body_size: OnceLock::new(),
+    unique_targets: OnceLock::new(),
+};
+
+/// Labels of dshp_http_version_requests_total
+const HTTP_VERSIONS: [&str; 5] = ["0.9", "1.0", "1.1", "2", "3"];
+
+/// Upstream response times kept per host for --measure-upstream-jitter
+const JITTER_WINDOW: usize = 100;
+/// Hosts tracked at once, so a client probing random hosts can't grow the map forever
+const MAX_JITTER_HOSTS: usize = 1024;
+/// Response times kept per domain for --per-domain-stats percentiles
+const DOMAIN_LATENCY_SAMPLES: usize = 256;
+/// Domains not requested for this long are dropped from --per-domain-stats
+const DOMAIN_IDLE: Duration = Duration::from_secs(24 * 60 * 60);
+
+pub struct Metrics {
+    pub requests_per_session: Histogram<7>,
+    pub max_requests_per_session: AtomicU64,
+    /// Connections waiting for a --connection-limit-soft slot
+    pub connection_queue_depth: AtomicU64,
+    pub connection_queue_timeouts: AtomicU64,
+    /// Connections turned away because --connection-queue-size were already waiting
+    pub connection_queue_full: AtomicU64,
+    /// Connections the kernel has completed but the proxy hasn't accepted yet
+    pub accept_queue_depth: AtomicU64,
+    /// Recent upstream response times by upstream host
+    pub upstream_latency: Mutex<BTreeMap<String, LatencyWindow>>,
+    /// Per target domain counters for --per-domain-stats
+    pub domains: Mutex<BTreeMap<String, DomainStats>>,
+    /// Client requests by HTTP version, in HTTP_VERSIONS order
+    http_versions: [AtomicU64; HTTP_VERSIONS.len()],
+    /// Upstream hostname lookups, IP literals excluded
+    pub dns_resolution_ms: Histogram<7>,
+    /// Declared Content-Length of forwarded requests, bucketed by --request-size-buckets
+    pub request_body_size: OnceLock<DynamicHistogram>,
+    /// Distinct target hosts since the top of the hour, under --server-name-indication-log
+    pub unique_targets: OnceLock<UniqueCounter>,
+}
+
+/// The last JITTER_WINDOW response times from one upstream
+#[derive(Default)]
+pub struct LatencyWindow {
+    samples_ms: VecDeque<f64>,
+    jitter_ms: f64,
+}
+
+pub struct DomainStats {
+    requests: u64,
+    bytes: u64,
+    errors: u64,
+    latency_sum_ms: f64,
+    /// The last DOMAIN_LATENCY_SAMPLES response times
+    latency_ms: VecDeque<f64>,
+    last_seen: Instant,
+}
+
+impl DomainStats {
+    fn new(now: Instant) -> DomainStats {
+        DomainStats {
+            requests: 0,
+            bytes: 0,
+            errors: 0,
+            latency_sum_ms: 0.0,
+            latency_ms: VecDeque::new(),
+            last_seen: now,
+        }
+    }
+
+    /// Nearest-rank percentile of the recent response times
+    fn latency_quantile(&self, q: f64) -> f64 {
+        let mut sorted: Vec<f64> = self.latency_ms.iter().copied().collect();
+        sorted.sort_by(f64::total_cmp);
+        let rank = ((q * sorted.len() as f64).ceil() as usize).clamp(1, sorted.len());
+        sorted[rank - 1]
+    }
+}
+
+impl Metrics {
+    /// Record a client connection closing after `requests` requests
+    pub fn session_closed(&self, requests: u64) {
+        self.requests_per_session.observe(requests);
+        self.max_requests_per_session.fetch_max(requests, Ordering::Relaxed);
+    }
+
+    pub fn request_version(&self, version: Version) {
+        let i = match version {
+            Version::HTTP_09 => 0,
+            Version::HTTP_10 => 1,
+            Version::HTTP_2 => 3,
+            Version::HTTP_3 => 4,
+            _ => 2,
+        };
+        self.http_versions[i].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record an upstream response time and return the host's jitter (the
+    /// standard deviation over the window) before and after it
+    pub fn upstream_responded(&self, host: &str, elapsed: Duration) -> (f64, f64) {
+        let mut hosts = self.upstream_latency.lock().unwrap();
+        if !hosts.contains_key(host) && hosts.len() >= MAX_JITTER_HOSTS {
+            return (0.0, 0.0);
+        }
+        let window = hosts.entry(host.to_string()).or_default();
+        if window.samples_ms.len() == JITTER_WINDOW {
+            window.samples_ms.pop_front();
+        }
+        window.samples_ms.push_back(elapsed.as_secs_f64() * 1000.0);
+        let n = window.samples_ms.len() as f64;
+        let mean = window.samples_ms.iter().sum::<f64>() / n;
+        let variance = window.samples_ms.iter().map(|s| (s - mean).powi(2)).sum::<f64>() / n;
+        let before = window.jitter_ms;
+        window.jitter_ms = variance.sqrt();
+        (before, window.jitter_ms)
+    }
+
+    /// Record a forwarded request to `domain`, keeping at most `limit`
+    /// domains. A new domain arriving at the limit replaces the least
+    /// requested one, so the busiest domains stay tracked.
+    pub fn domain_requested(&self, domain: &str, limit: usize, elapsed: Duration, error: bool) {
+        let now = Instant::now();
+        let mut domains = self.domains.lock().unwrap();
+        if !domains.contains_key(domain) && domains.len() >= limit {
+            domains.retain(|_, stats| now.duration_since(stats.last_seen) < DOMAIN_IDLE);
+            if domains.len() >= limit
+                && let Some(quietest) = domains.iter().min_by_key(|(_, stats)| stats.requests).map(|(d, _)| d.clone())
+            {
+                domains.remove(&quietest);
+            }
+        }
+        let stats = domains.entry(domain.to_string()).or_insert_with(|| DomainStats::new(now));
+        let ms = elapsed.as_secs_f64() * 1000.0;
+        stats.requests += 1;
+        stats.errors += error as u64;
+        stats.latency_sum_ms += ms;
+        if stats.latency_ms.len() == DOMAIN_LATENCY_SAMPLES {
+            stats.latency_ms.pop_front();
+        }
+        stats.latency_ms.push_back(ms);
+        stats.last_seen = now;
+    }
+
+    /// Add response body bytes sent for `domain`, unless it has been
+    /// replaced by a busier one since the request was recorded
+    pub fn domain_bytes(&self, domain: &str, bytes: u64) {
+        if let Some(stats) = self.domains.lock().unwrap().get_mut(domain) {
+            stats.bytes += bytes;
+        }
+    }
+
+    /// --per-domain-stats as a JSON object keyed by domain
+    fn domains_json(&self) -> String {
+        let domains = self.domains.lock().unwrap();
+        let map: serde_json::Map<_, _> = domains
+            .iter()
+            .map(|(domain, stats)| {
+                let value = json!({
+                    "requests": stats.requests,
+                    "bytes": stats.bytes,
+                    "errors": stats.errors,
+                    "p50_ms": stats.latency_quantile(0.5),
+                    "p95_ms": stats.latency_quantile(0.95),
+                    "p99_ms": stats.latency_quantile(0.99),
+                });
+                (domain.clone(), value)
+            })
+            .collect();
+        serde_json::Value::Object(map).to_string()
+    }
+}
+
+/// Cumulative Prometheus histogram with fixed upper bounds
+pub struct Histogram<const N: usize> {
+    bounds: [u64; N],
+    /// One counter per bound, plus the `+Inf` bucket
+    buckets: [AtomicU64; N],
+    inf: AtomicU64,
+    sum: AtomicU64,
+}
+
+impl<const N: usize> Histogram<N> {
+    pub const fn new(bounds: [u64; N]) -> Self {
+        Histogram {
+            bounds,
+            buckets: [const { AtomicU64::new(0) }; N],
+            inf: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, value: u64) {
+        match self.bounds.iter().position(|&b| value <= b) {
+            Some(i) => &self.buckets[i],
+            None => &self.inf,
+        }
+        .fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+    }
+
+    fn count(&self) -> u64 {
+        count(&self.buckets, &self.inf)
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        render_histogram(out, name, help, &self.bounds, &self.buckets, &self.inf, &self.sum);
+    }
+}
+
+/// A histogram whose bounds come from the command line
+pub struct DynamicHistogram {
+    bounds: Vec<u64>,
+    buckets: Vec<AtomicU64>,
+    inf: AtomicU64,
+    sum: AtomicU64,
+}
+
+impl DynamicHistogram {
+    pub fn new(bounds: Vec<u64>) -> Self {
+        DynamicHistogram {
+            buckets: bounds.iter().map(|_| AtomicU64::new(0)).collect(),
+            bounds,
+            inf: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, value: u64) {
+        match self.bounds.iter().position(|&b| value <= b) {
+            Some(i) => &self.buckets[i],
+            None => &self.inf,
+        }
+        .fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        render_histogram(out, name, help, &self.bounds, &self.buckets, &self.inf, &self.sum);
+    }
+}
+
+fn count(buckets: &[AtomicU64], inf: &AtomicU64) -> u64 {
+    buckets.iter().map(|b| b.load(Ordering::Relaxed)).sum::<u64>() + inf.load(Ordering::Relaxed)
+}
+
+fn render_histogram(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    bounds: &[u64],
+    buckets: &[AtomicU64],
+    inf: &AtomicU64,
+    sum: &AtomicU64,
+) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} histogram", name, help, name);
+    let mut cumulative = 0;
+    for (bound, bucket) in bounds.iter().zip(buckets) {
+        cumulative += bucket.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+    }
+    let count = count(buckets, inf);
+    let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+    let _ = writeln!(out, "{}_sum {}", name, sum.load(Ordering::Relaxed));
+    let _ = writeln!(out, "{}_count {}", name, count);
+}
+
+/// Whether `req` carries `Authorization: Bearer <token>`
+fn has_bearer(req: &Request<Body>, token: &str) -> bool {
+    let Some(presented) = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+    // Compare every byte so timing doesn't reveal how much of a guess was right
+    presented.len() == token.len() && presented.bytes().zip(token.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn unauthorized() -> Response<Body> {
+    let mut resp = Response::new(Body::from("Unauthorized"));
+    *resp.status_mut() = StatusCode::UNAUTHORIZED;
+    resp.headers_mut()
+        .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer realm=\"metrics\""));
+    resp
+}
+
+/// Bind `addr` and return a future serving Prometheus text-format
+/// metrics at `GET /metrics`, and with `recent` the request ring buffer
+/// at `GET /admin/recent-requests`
+pub fn serve(
+    addr: SocketAddr,
+    recent: Option<Arc<RecentRequests>>,
+    domain_stats: bool,
+    token: Option<Arc<str>>,
+) -> hyper::Result<impl Future<Output = hyper::Result<()>>> {
+    let make_svc = hyper::service::make_service_fn(move |_| {
+        let recent = recent.clone();
+        let token = token.clone();
+        async move {
+            Ok::<_, Infallible>(hyper::service::service_fn(move |req| {
+                let recent = recent.clone();
+                let authorized = token.as_deref().is_none_or(|token| has_bearer(&req, token));
+                async move {
+                    if !authorized {
+                        return Ok(unauthorized());
+                    }
+                    handle(req, recent, domain_stats).await
+                }
+            }))
+        }
+    });
+    let server = Server::try_bind(&addr)?.serve(make_svc);
+    log!("Metrics on http://{}/metrics", addr);
+    Ok(server)
+}
+
+async fn handle(
+    req: Request<Body>,
+    recent: Option<Arc<RecentRequests>>,
+    domain_stats: bool,
+) -> Result<Response<Body>, Infallible> {
+    if req.method() == Method::GET
+        && req.uri().path() == "/admin/recent-requests"
+        && let Some(recent) = recent
+    {
+        let mut resp = Response::new(Body::from(recent.to_json()));
+        resp.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        return Ok(resp);
+    }
+    if domain_stats && req.method() == Method::GET && req.uri().path() == "/stats/domains" {
+        let mut resp = Response::new(Body::from(METRICS.domains_json()));
+        resp.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        return Ok(resp);
+    }
+    if req.method() != Method::GET || req.uri().path() != "/metrics" {
+        let mut resp = Response::new(Body::from("Not Found"));
+        *resp.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(resp);
+    }
+
+    let mut resp = Response::new(Body::from(render()));
+    resp.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; version=0.0.4"),
+    );
+    Ok(resp)
+}
+
+/// PUT the current metrics to a Prometheus Pushgateway job URL
+/// (`<gateway>/metrics/job/<job>`), replacing the previous push
+pub async fn push(client: &Client<Connector>, url: &Uri) -> Result<(), String> {
+    let req = Request::put(url.clone())
+        .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(Body::from(render()))
+        .map_err(|e| e.to_string())?;
+    let resp = client.request(req).await.map_err(|e| e.to_string())?;
+    if resp.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("pushgateway responded {}", resp.status()))
+    }
+}
+
+fn render() -> String {
+    let mut out = String::new();
+
+    let requests = crate::REQ_COUNTER.load(Ordering::Relaxed);
+    counter(&mut out, "dshp_requests_total", "Requests received", requests);
+
+    let m = &METRICS;
+    let _ = writeln!(out, "# HELP dshp_http_version_requests_total Requests received by client HTTP version");
+    let _ = writeln!(out, "# TYPE dshp_http_version_requests_total counter");
+    for (version, count) in HTTP_VERSIONS.iter().zip(&m.http_versions) {
+        let _ = writeln!(
+            out,
+            "dshp_http_version_requests_total{{version=\"{}\"}} {}",
+            version,
+            count.load(Ordering::Relaxed)
+        );
+    }
+    m.requests_per_session.render(
+        &mut out,
+        "dshp_requests_per_session",
+        "Requests served per closed client connection",
+    );
+    gauge(
+        &mut out,
+        "dshp_max_requests_per_session",
+        "Most requests served on a single client connection",
+        m.max_requests_per_session.load(Ordering::Relaxed),
+    );
+    let sessions = m.requests_per_session.count();
+    let avg = if sessions == 0 {
+        0.0
+    } else {
+        m.requests_per_session.sum.load(Ordering::Relaxed) as f64 / sessions as f64
+    };
+    let _ = writeln!(
+        out,
+        "# HELP dshp_avg_requests_per_session Mean requests per closed client connection\n\
+         # TYPE dshp_avg_requests_per_session gauge\ndshp_avg_requests_per_session {}",
+        avg
+    );
+    gauge(
+        &mut out,
+        "dshp_connection_queue_depth",
+        "Connections waiting for a slot under --connection-limit-soft",
+        m.connection_queue_depth.load(Ordering::Relaxed),
+    );
+    counter(
+        &mut out,
+        "dshp_connection_queue_timeouts_total",
+        "Queued connections rejected with 503 after --connection-queue-timeout",
+        m.connection_queue_timeouts.load(Ordering::Relaxed),
+    );
+    counter(
+        &mut out,
+        "dshp_connection_queue_full_total",
+        "Connections rejected with 503 because the --connection-limit-soft queue was full",
+        m.connection_queue_full.load(Ordering::Relaxed),
+    );
+    if cfg!(target_os = "linux") {
+        gauge(
+            &mut out,
+            "dshp_accept_queue_depth",
+            "Connections waiting in the listening socket's accept queue",
+            m.accept_queue_depth.load(Ordering::Relaxed),
+        );
+    }
+
+    if let Some(sizes) = m.request_body_size.get() {
+        sizes.render(
+            &mut out,
+            "dshp_request_body_size_bytes",
+            "Content-Length of forwarded requests that declared one",
+        );
+    }
+    if let Some(targets) = m.unique_targets.get() {
+        gauge(
+            &mut out,
+            "dshp_approx_unique_targets_last_hour",
+            "Approximate distinct CONNECT and HTTP target hosts since the top of the hour (UTC)",
+            targets.estimate(),
+        );
+    }
+    m.dns_resolution_ms.render(
+        &mut out,
+        "dshp_dns_resolution_duration_ms",
+        "Time to resolve upstream hostnames, in milliseconds",
+    );
+
+    let hosts = m.upstream_latency.lock().unwrap();
+    if !hosts.is_empty() {
+        let _ = writeln!(
+            out,
+            "# HELP dshp_upstream_latency_jitter_ms Standard deviation of the last {} upstream response times\n\
+             # TYPE dshp_upstream_latency_jitter_ms gauge",
+            JITTER_WINDOW
+        );
+        for (host, window) in hosts.iter() {
+            let _ = writeln!(out, "dshp_upstream_latency_jitter_ms{{upstream=\"{}\"}} {:.3}", host, window.jitter_ms);
+        }
+    }
+    drop(hosts);
+
+    let mut domains = m.domains.lock().unwrap();
+    let now = Instant::now();
+    domains.retain(|_, stats| now.duration_since(stats.last_seen) < DOMAIN_IDLE);
+    if !domains.is_empty() {
+        render_domains(&mut out, &domains);
+    }
+    drop(domains);
+
+    render_runtime(&mut out);
+    out
+}
+
+fn render_domains(out: &mut String, domains: &BTreeMap<String, DomainStats>) {
+    let _ = writeln!(out, "# HELP dshp_domain_requests_total Requests forwarded per target domain");
+    let _ = writeln!(out, "# TYPE dshp_domain_requests_total counter");
+    for (domain, stats) in domains {
+        let _ = writeln!(out, "dshp_domain_requests_total{{domain=\"{}\"}} {}", domain, stats.requests);
+    }
+    let _ = writeln!(out, "# HELP dshp_domain_response_bytes_total Response body bytes sent to clients per target domain");
+    let _ = writeln!(out, "# TYPE dshp_domain_response_bytes_total counter");
+    for (domain, stats) in domains {
+        let _ = writeln!(out, "dshp_domain_response_bytes_total{{domain=\"{}\"}} {}", domain, stats.bytes);
+    }
+    let _ = writeln!(out, "# HELP dshp_domain_errors_total Upstream failures and 5xx responses per target domain");
+    let _ = writeln!(out, "# TYPE dshp_domain_errors_total counter");
+    for (domain, stats) in domains {
+        let _ = writeln!(out, "dshp_domain_errors_total{{domain=\"{}\"}} {}", domain, stats.errors);
+    }
+    let _ = writeln!(
+        out,
+        "# HELP dshp_domain_latency_ms Upstream response time per target domain, quantiles over the last {} requests",
+        DOMAIN_LATENCY_SAMPLES
+    );
+    let _ = writeln!(out, "# TYPE dshp_domain_latency_ms summary");
+    for (domain, stats) in domains {
+        for q in [0.5, 0.95, 0.99] {
+            let _ = writeln!(
+                out,
+                "dshp_domain_latency_ms{{domain=\"{}\",quantile=\"{}\"}} {:.3}",
+                domain,
+                q,
+                stats.latency_quantile(q)
+            );
+        }
+        let _ = writeln!(out, "dshp_domain_latency_ms_sum{{domain=\"{}\"}} {:.3}", domain, stats.latency_sum_ms);
+        let _ = writeln!(out, "dshp_domain_latency_ms_count{{domain=\"{}\"}} {}", domain, stats.requests);
+    }
+}
+
+/// Tokio runtime internals. Steal and I/O driver counts are only
+/// available when built with `--cfg tokio_unstable`, so they are left out.
+fn render_runtime(out: &mut String) {
+    let rt = tokio::runtime::Handle::current().metrics();
+    gauge(out, "tokio_workers", "Runtime worker threads", rt.num_workers() as u64);
+    gauge(out, "tokio_alive_tasks", "Tasks currently alive", rt.num_alive_tasks() as u64);
+    gauge(
+        out,
+        "tokio_global_queue_depth",
+        "Tasks waiting in the global injection queue",
+        rt.global_queue_depth() as u64,
+    );
+
+    let _ = writeln!(out, "# HELP tokio_worker_park_total Times each worker parked waiting for work");
+    let _ = writeln!(out, "# TYPE tokio_worker_park_total counter");
+    for w in 0..rt.num_workers() {
+        let _ = writeln!(out, "tokio_worker_park_total{{worker=\"{}\"}} {}", w, rt.worker_park_count(w));
+    }
+    let _ = writeln!(out, "# HELP tokio_worker_busy_seconds_total Time each worker spent executing tasks");
+    let _ = writeln!(out, "# TYPE tokio_worker_busy_seconds_total counter");
+    for w in 0..rt.num_workers() {
+        let busy = rt.worker_total_busy_duration(w).as_secs_f64();
+        let _ = writeln!(out, "tokio_worker_busy_seconds_total{{worker=\"{}\"}} {}", w, busy);
+    }
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter\n{} {}", name, help, name, name, value);
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} gauge\n{} {}", name, help, name, name, value);
+}
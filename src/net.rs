@@ -0,0 +1,305 @@
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+
+use tokio::net::{TcpSocket, TcpStream};
+
+use crate::cidr::Cidr;
+use crate::dns::{Resolved, Resolver};
+
+/// Socket options for outbound CONNECT connections
+#[derive(Debug, Default)]
+pub struct ConnectOptions {
+    pub tcp_fastopen: bool,
+    /// Outbound interface by target address range (Linux only)
+    pub interfaces: Option<InterfaceRoutes>,
+    /// Marked in the ToS / traffic class byte of every packet (Linux only)
+    pub dscp: Option<Dscp>,
+}
+
+/// A Differentiated Services code point, by number (0-63) or by name
+/// (`EF`, `AF11`-`AF43`, `CS0`-`CS7`, `BE`)
+#[derive(Debug, Clone, Copy)]
+pub struct Dscp(u8);
+
+impl Dscp {
+    /// The ToS byte: the code point in the upper six bits, ECN left clear
+    pub fn tos(self) -> u32 {
+        (self.0 as u32) << 2
+    }
+}
+
+impl FromStr for Dscp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Dscp, String> {
+        let name = s.trim().to_ascii_uppercase();
+        let digit = |i: usize| name[i..].parse::<u8>().ok();
+        let value = match name.as_str() {
+            "EF" => Some(46),
+            "BE" | "DEFAULT" => Some(0),
+            _ if name.starts_with("AF") && name.len() == 4 => {
+                match (digit(2).map(|n| n / 10), digit(3)) {
+                    (Some(class @ 1..=4), Some(drop @ 1..=3)) => Some(class * 8 + drop * 2),
+                    _ => None,
+                }
+            }
+            _ if name.starts_with("CS") && name.len() == 3 => digit(2).filter(|&n| n <= 7).map(|n| n * 8),
+            _ => name.parse::<u8>().ok().filter(|&n| n <= 63),
+        };
+        value.map(Dscp).ok_or_else(|| format!("{:?}: expected 0-63, EF, AF11-AF43, CS0-CS7 or BE", s))
+    }
+}
+
+/// `prefix=interface` pairs such as `10.0.0.0/8=eth1,0.0.0.0/0=eth0`
+#[derive(Debug, Clone)]
+pub struct InterfaceRoutes(Vec<(Cidr, String)>);
+
+impl InterfaceRoutes {
+    /// Interface of the most specific range containing `ip`
+    pub fn lookup(&self, ip: IpAddr) -> Option<&str> {
+        self.0
+            .iter()
+            .filter(|(range, _)| range.contains(ip))
+            .max_by_key(|(range, _)| range.prefix())
+            .map(|(_, interface)| interface.as_str())
+    }
+}
+
+impl FromStr for InterfaceRoutes {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<InterfaceRoutes, String> {
+        let mut routes = Vec::new();
+        for pair in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (range, interface) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("{:?}: expected prefix=interface", pair))?;
+            let range = Cidr::parse(range.trim()).ok_or_else(|| format!("{:?}: invalid address range", range))?;
+            let interface = interface.trim();
+            // IFNAMSIZ is 16 including the terminating NUL
+            if interface.is_empty() || interface.len() > 15 {
+                return Err(format!("{:?}: invalid interface name", interface));
+            }
+            routes.push((range, interface.to_string()));
+        }
+        if routes.is_empty() {
+            return Err("expected at least one prefix=interface pair".to_string());
+        }
+        Ok(InterfaceRoutes(routes))
+    }
+}
+
+impl fmt::Display for InterfaceRoutes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (range, interface)) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{}={}", range, interface)?;
+        }
+        Ok(())
+    }
+}
+
+/// Resolve `target` (host:port) and connect to the first address that
+/// accepts; also returns how the lookup went
+pub async fn connect(target: &str, resolver: &Resolver, opts: &ConnectOptions) -> io::Result<(TcpStream, Resolved)> {
+    let (host, port) = target
+        .rsplit_once(':')
+        .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "expected host:port"))?;
+    let (addrs, resolved) = resolver.lookup_traced(host, port).await?;
+    let mut last_err = None;
+    for addr in addrs {
+        match connect_addr(addr, opts).await {
+            Ok(stream) => return Ok((stream, resolved)),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no addresses resolved")))
+}
+
+async fn connect_addr(addr: SocketAddr, opts: &ConnectOptions) -> io::Result<TcpStream> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    #[cfg(target_os = "linux")]
+    if opts.tcp_fastopen {
+        // Best-effort: kernels without TFO client support just do a normal handshake
+        let _ = set_fastopen_connect(&socket);
+    }
+    // IP_TOS only marks IPv4 packets; IPv6 carries the DSCP in its traffic class
+    #[cfg(target_os = "linux")]
+    if let Some(dscp) = opts.dscp {
+        if addr.is_ipv4() {
+            socket.set_tos_v4(dscp.tos())?;
+        } else {
+            set_tclass_v6(&socket, dscp.tos())?;
+        }
+    }
+    #[cfg(target_os = "linux")]
+    if let Some(interface) = opts.interfaces.as_ref().and_then(|routes| routes.lookup(addr.ip())) {
+        socket.bind_device(Some(interface.as_bytes()))?;
+    }
+    socket.connect(addr).await
+}
+
+#[cfg(target_os = "linux")]
+fn set_fastopen_connect(socket: &TcpSocket) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let on: libc::c_int = 1;
+    // SAFETY: the fd is owned by `socket` and `on` outlives the call
+    let rc = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_FASTOPEN_CONNECT,
+            &on as *const libc::c_int as *const libc::c_void,
+            size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if rc == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_tclass_v6(socket: &TcpSocket, tclass: u32) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let value = tclass as libc::c_int;
+    // SAFETY: the fd is owned by `socket` and `value` outlives the call
+    let rc = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IPV6,
+            libc::IPV6_TCLASS,
+            &value as *const libc::c_int as *const libc::c_void,
+            size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if rc == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Loopback, private, link-local, CGNAT, multicast and similar non-public
+/// addresses, including IPv4 ones wrapped in IPv6 (mapped, compatible,
+/// NAT64 and 6to4)
+pub fn is_private_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, ..] = v4.octets();
+            v4.is_private()
+                || v4.is_loopback()
+                || v4.is_link_local()
+                || v4.is_multicast()
+                // 0.0.0.0/8 ("this network") and 240.0.0.0/4 (reserved, broadcast)
+                || a == 0
+                || a >= 240
+                || (a == 100 && (64..128).contains(&b))
+        }
+        IpAddr::V6(v6) => {
+            let segments = v6.segments();
+            let embedded = |hi: u16, lo: u16| is_private_ip(IpAddr::V4(Ipv4Addr::from((u32::from(hi) << 16) | u32::from(lo))));
+            if let Some(v4) = v6.to_ipv4_mapped() {
+                return is_private_ip(IpAddr::V4(v4));
+            }
+            match segments {
+                // IPv4-compatible ::a.b.c.d (:: and ::1 are caught below)
+                [0, 0, 0, 0, 0, 0, hi, lo] if hi != 0 => return embedded(hi, lo),
+                // NAT64, well-known prefix
+                [0x64, 0xff9b, 0, 0, 0, 0, hi, lo] => return embedded(hi, lo),
+                // 6to4
+                [0x2002, hi, lo, ..] => return embedded(hi, lo),
+                _ => {}
+            }
+            let first = segments[0];
+            v6.is_loopback()
+                || v6.is_unspecified()
+                || v6.is_multicast()
+                || (first & 0xfe00) == 0xfc00
+                // Link-local and the deprecated site-local fec0::/10
+                || (first & 0xffc0) == 0xfe80
+                || (first & 0xffc0) == 0xfec0
+                // Local-use NAT64
+                || (first == 0x64 && segments[1] == 0xff9b && segments[2] == 1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dscp_names_and_numbers() {
+        let tos = |s: &str| s.parse::<Dscp>().map(Dscp::tos);
+        assert_eq!(tos("EF"), Ok(46 << 2));
+        assert_eq!(tos(" ef "), Ok(46 << 2));
+        assert_eq!(tos("BE"), Ok(0));
+        assert_eq!(tos("default"), Ok(0));
+        assert_eq!(tos("AF11"), Ok(10 << 2));
+        assert_eq!(tos("af43"), Ok(38 << 2));
+        assert_eq!(tos("CS0"), Ok(0));
+        assert_eq!(tos("CS7"), Ok(56 << 2));
+        assert_eq!(tos("0"), Ok(0));
+        assert_eq!(tos("63"), Ok(63 << 2));
+        for bad in ["64", "-1", "AF10", "AF44", "AF51", "AF1", "CS8", "CS", "XX", ""] {
+            assert!(tos(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn private_ips() {
+        let cases = [
+            ("10.1.2.3", true),
+            ("172.16.0.1", true),
+            ("192.168.1.1", true),
+            ("127.0.0.1", true),
+            ("169.254.169.254", true),
+            ("100.64.0.1", true),
+            ("0.0.0.0", true),
+            ("0.1.2.3", true),
+            ("224.0.0.1", true),
+            ("239.255.255.250", true),
+            ("240.0.0.1", true),
+            ("255.255.255.255", true),
+            ("8.8.8.8", false),
+            ("100.128.0.1", false),
+            ("93.184.216.34", false),
+            ("::", true),
+            ("::1", true),
+            ("fc00::1", true),
+            ("fd12:3456::1", true),
+            ("fe80::1", true),
+            ("fec0::1", true),
+            ("ff02::1", true),
+            ("::ffff:10.0.0.1", true),
+            ("::ffff:8.8.8.8", false),
+            ("::10.0.0.1", true),
+            ("::127.0.0.1", true),
+            ("::8.8.8.8", false),
+            ("64:ff9b::10.0.0.1", true),
+            ("64:ff9b::169.254.169.254", true),
+            ("64:ff9b::8.8.8.8", false),
+            ("64:ff9b:1::1", true),
+            ("2002:0a00:0001::1", true),
+            ("2002:7f00:0001::", true),
+            ("2002:0808:0808::1", false),
+            ("2001:4860:4860::8888", false),
+        ];
+        for (ip, private) in cases {
+            assert_eq!(is_private_ip(ip.parse().unwrap()), private, "{}", ip);
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use hyper::Method;
+use serde_json::json;
+
+use crate::request_id::RequestId;
+
+/// One finished request, as served at `GET /admin/recent-requests`
+pub struct RequestSummary {
+    pub req_id: RequestId,
+    pub method: Method,
+    pub uri: String,
+    pub status: u16,
+    /// Until the response head was ready
+    pub duration: Duration,
+    pub client_ip: IpAddr,
+}
+
+/// The last `capacity` requests, oldest first
+pub struct RecentRequests {
+    capacity: usize,
+    entries: Mutex<VecDeque<RequestSummary>>,
+}
+
+impl RecentRequests {
+    pub fn new(capacity: usize) -> RecentRequests {
+        RecentRequests {
+            capacity,
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub fn push(&self, summary: RequestSummary) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(summary);
+    }
+
+    pub fn to_json(&self) -> String {
+        let entries = self.entries.lock().unwrap();
+        let list: Vec<_> = entries
+            .iter()
+            .map(|e| {
+                json!({
+                    "req_id": e.req_id.to_string(),
+                    "method": e.method.as_str(),
+                    "uri": e.uri,
+                    "status": e.status,
+                    "duration_ms": e.duration.as_millis() as u64,
+                    "client_ip": e.client_ip.to_string(),
+                })
+            })
+            .collect();
+        serde_json::Value::Array(list).to_string()
+    }
+}
@@ -0,0 +1,170 @@
+use std::net::SocketAddr;
+
+use hyper::body::HttpBody;
+use hyper::client::Client;
+use hyper::header::{HeaderValue, AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, COOKIE, HOST, LOCATION};
+use hyper::{Body, Method, Request, Response, StatusCode, Uri, Version};
+
+use crate::dns::{Connector, Resolver};
+use crate::net::is_private_ip;
+use crate::request_id::RequestId;
+
+pub struct RedirectPolicy {
+    /// Maximum redirects to follow; 0 disables following
+    pub max: usize,
+    pub allow_private_ips: bool,
+    /// Resolves redirect targets for the private address check
+    pub resolver: Resolver,
+}
+
+impl RedirectPolicy {
+    /// Only bodiless requests are followed, since the body can't be replayed
+    pub fn applies_to(&self, req: &Request<Body>) -> bool {
+        self.max > 0 && req.body().is_end_stream()
+    }
+
+    /// Send `req` and follow up to `max` redirects to other plain-HTTP URLs.
+    /// Whenever a redirect is not followed (limit, loop, https target,
+    /// private address) the redirect response itself is returned.
+    /// Redirects may lead anywhere, so they are followed with `follow_client`
+    /// over HTTP/1.1 rather than with the upstream's HTTP/2.
+    pub async fn send(
+        &self,
+        client: &Client<Connector>,
+        follow_client: &Client<Connector>,
+        req: Request<Body>,
+        req_id: RequestId,
+        debug: bool,
+    ) -> hyper::Result<Response<Body>> {
+        let mut method = req.method().clone();
+        let mut headers = req.headers().clone();
+        let version = match req.version() {
+            Version::HTTP_2 => Version::HTTP_11,
+            version => version,
+        };
+        let mut uri = req.uri().clone();
+        let mut visited = vec![uri.clone()];
+
+        let mut resp = client.request(req).await?;
+        for _ in 0..self.max {
+            let status = resp.status();
+            if !matches!(status.as_u16(), 301 | 302 | 303 | 307 | 308) {
+                break;
+            }
+            let Some(next) = location(&resp, &uri) else {
+                break;
+            };
+            if next.scheme_str() != Some("http") {
+                break;
+            }
+            if visited.contains(&next) {
+                if debug {
+                    log!("[req {}] redirect loop at {}, not following", req_id, next);
+                }
+                break;
+            }
+            // Connect to exactly the address that was checked; resolving
+            // again would let a rebinding name switch to a private one
+            let pinned = if self.allow_private_ips {
+                None
+            } else {
+                match public_addr(&self.resolver, &next).await {
+                    Some(addr) => Some(addr),
+                    None => {
+                        if debug {
+                            log!("[req {}] redirect to private address {} refused", req_id, next);
+                        }
+                        break;
+                    }
+                }
+            };
+
+            if status == StatusCode::SEE_OTHER && method != Method::HEAD {
+                method = Method::GET;
+                headers.remove(CONTENT_TYPE);
+            }
+            // Don't hand credentials to a different origin
+            if next.authority() != uri.authority() {
+                headers.remove(AUTHORIZATION);
+                headers.remove(COOKIE);
+            }
+            headers.remove(CONTENT_LENGTH);
+            if let Some(auth) = next.authority()
+                && let Ok(host) = HeaderValue::from_str(auth.as_str())
+            {
+                headers.insert(HOST, host);
+            }
+
+            if debug {
+                log!("[req {}] following {} redirect to {}", req_id, status.as_u16(), next);
+            }
+            visited.push(next.clone());
+            uri = next;
+
+            let mut follow = Request::new(Body::empty());
+            *follow.method_mut() = method.clone();
+            // Host still names the redirect target
+            *follow.uri_mut() = match pinned {
+                Some(addr) => pinned_uri(&uri, addr).unwrap_or_else(|| uri.clone()),
+                None => uri.clone(),
+            };
+            *follow.headers_mut() = headers.clone();
+            *follow.version_mut() = version;
+            resp = follow_client.request(follow).await?;
+        }
+        Ok(resp)
+    }
+}
+
+/// Resolve the Location header of `resp` against the URI that produced it
+fn location(resp: &Response<Body>, base: &Uri) -> Option<Uri> {
+    let loc = resp.headers().get(LOCATION)?.to_str().ok()?;
+    let scheme = base.scheme_str().unwrap_or("http");
+    let authority = base.authority()?.as_str();
+
+    let absolute = if loc.contains("://") {
+        loc.to_string()
+    } else if let Some(rest) = loc.strip_prefix("//") {
+        format!("{}://{}", scheme, rest)
+    } else if loc.starts_with('/') {
+        format!("{}://{}{}", scheme, authority, loc)
+    } else {
+        // Relative to the directory of the current path
+        let path = base.path();
+        let dir = &path[..path.rfind('/').map_or(0, |i| i + 1)];
+        let dir = if dir.is_empty() { "/" } else { dir };
+        format!("{}://{}{}{}", scheme, authority, dir, loc)
+    };
+    absolute.parse().ok()
+}
+
+/// The address to connect to for `uri`, if none of those it resolves to is
+/// private. Unresolvable targets would fail anyway and aren't followed.
+async fn public_addr(resolver: &Resolver, uri: &Uri) -> Option<SocketAddr> {
+    let host = uri.host()?;
+    let port = uri.port_u16().unwrap_or(80);
+    let addrs = resolver.lookup(host, port).await.ok()?;
+    if addrs.iter().any(|a| is_private_ip(a.ip())) {
+        return None;
+    }
+    addrs.first().copied()
+}
+
+/// `uri` with its authority replaced by `addr`
+fn pinned_uri(uri: &Uri, addr: SocketAddr) -> Option<Uri> {
+    let path = uri.path_and_query().map_or("/", |pq| pq.as_str());
+    format!("http://{}{}", addr, path).parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pinned_uri_keeps_path_and_query() {
+        let uri: Uri = "http://rebind.example:8080/a/b?c=d".parse().unwrap();
+        assert_eq!(pinned_uri(&uri, "203.0.113.7:8080".parse().unwrap()).unwrap(), "http://203.0.113.7:8080/a/b?c=d");
+        let uri: Uri = "http://example.com".parse().unwrap();
+        assert_eq!(pinned_uri(&uri, "[2001:db8::1]:80".parse().unwrap()).unwrap(), "http://[2001:db8::1]:80/");
+    }
+}
@@ -0,0 +1,49 @@
+use std::fmt;
+
+use regex::Regex;
+use ulid::Ulid;
+
+/// Longest X-Request-ID adopted by --request-id-propagate-incoming
+const MAX_LEN: usize = 64;
+
+/// Identifies one request in every log line it produces. A fixed buffer
+/// rather than a String, so it stays `Copy` like the ULID it usually is.
+#[derive(Clone, Copy)]
+pub struct RequestId {
+    buf: [u8; MAX_LEN],
+    len: u8,
+}
+
+impl RequestId {
+    /// A fresh ULID: time-ordered and unique across proxy instances
+    pub fn generate() -> RequestId {
+        RequestId::copy_of(&Ulid::new().to_string())
+    }
+
+    /// The client's own ID, if it is short enough and `format` matches all of it
+    pub fn incoming(value: &str, format: &Regex) -> Option<RequestId> {
+        (value.len() <= MAX_LEN && format.is_match(value)).then(|| RequestId::copy_of(value))
+    }
+
+    fn copy_of(s: &str) -> RequestId {
+        let mut buf = [0; MAX_LEN];
+        buf[..s.len()].copy_from_slice(s.as_bytes());
+        RequestId { buf, len: s.len() as u8 }
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only ever filled from a whole &str
+        std::str::from_utf8(&self.buf[..self.len as usize]).unwrap_or_default()
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Compile --request-id-format so it has to match the whole ID
+pub fn parse_format(s: &str) -> Result<Regex, String> {
+    Regex::new(&format!("^(?:{})$", s)).map_err(|e| e.to_string())
+}
@@ -0,0 +1,361 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+
+use bytes::BytesMut;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::{mpsc, watch};
+
+use crate::request_id::RequestId;
+
+/// How many bytes of whichever side speaks first are inspected
+const SNIFF_LEN: usize = 64;
+/// Size of each --tunnel-read-buffer-count buffer, the same as tokio::io::copy uses
+const PIPE_BUFFER_SIZE: usize = 8 * 1024;
+
+/// What the first bytes of a CONNECT tunnel look like
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Tls,
+    /// A recognised plaintext protocol other than HTTP
+    Plain(&'static str),
+    /// Plaintext HTTP, which ordinary clients (WebSockets over port 80,
+    /// say) tunnel too, so --block-non-tls-connect leaves it alone
+    Http(&'static str),
+    Unknown,
+}
+
+impl Protocol {
+    /// Whether a tunnel opening like this gets closed: other plaintext
+    /// protocols under `block`, HTTP only if `block_http` as well
+    pub fn blocked(self, block: bool, block_http: bool) -> bool {
+        match self {
+            Protocol::Plain(_) => block,
+            Protocol::Http(_) => block && block_http,
+            Protocol::Tls | Protocol::Unknown => false,
+        }
+    }
+
+    /// The plaintext protocol's name
+    pub fn plain_name(self) -> Option<&'static str> {
+        match self {
+            Protocol::Plain(name) | Protocol::Http(name) => Some(name),
+            Protocol::Tls | Protocol::Unknown => None,
+        }
+    }
+}
+
+/// Classify the first bytes sent by either end of a tunnel. Most plaintext
+/// protocols are server-first (banners), TLS and SSH clients speak first
+pub fn classify(first: &[u8]) -> Protocol {
+    const SIGNATURES: &[(&[u8], &str)] = &[
+        (b"SSH-", "ssh"),
+        (b"220 ", "smtp/ftp"),
+        (b"220-", "smtp/ftp"),
+        (b"EHLO ", "smtp"),
+        (b"HELO ", "smtp"),
+        (b"* OK", "imap"),
+        (b"+OK", "pop3"),
+        (b"NICK ", "irc"),
+        (b"CAP LS", "irc"),
+    ];
+    const HTTP_SIGNATURES: &[(&[u8], &str)] = &[
+        (b"GET ", "http"),
+        (b"POST ", "http"),
+        (b"HEAD ", "http"),
+        // Client connection preface of prior-knowledge HTTP/2
+        (b"PRI * HTTP/2.0", "http2"),
+    ];
+    // Record type handshake, protocol major version 3
+    if first.len() >= 2 && first[0] == 0x16 && first[1] == 0x03 {
+        return Protocol::Tls;
+    }
+    let matching = |signatures: &[(&[u8], &'static str)]| {
+        signatures.iter().find(|(sig, _)| first.starts_with(sig)).map(|&(_, name)| name)
+    };
+    if let Some(name) = matching(SIGNATURES) {
+        Protocol::Plain(name)
+    } else if let Some(name) = matching(HTTP_SIGNATURES) {
+        Protocol::Http(name)
+    } else {
+        Protocol::Unknown
+    }
+}
+
+/// Wait for the first bytes from either side and classify them. Unless
+/// [`Protocol::blocked`] says so, those bytes are then delivered to the
+/// other side, so the tunnel carries on as if nothing had been read
+pub async fn sniff<C, S>(client: &mut C, server: &mut S, block: bool, block_http: bool) -> io::Result<Protocol>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut from_client = [0u8; SNIFF_LEN];
+    let mut from_server = [0u8; SNIFF_LEN];
+    let (first, protocol) = tokio::select! {
+        n = client.read(&mut from_client) => {
+            let first = &from_client[..n?];
+            let protocol = classify(first);
+            if !protocol.blocked(block, block_http) {
+                server.write_all(first).await?;
+            }
+            (first.len(), protocol)
+        }
+        n = server.read(&mut from_server) => {
+            let first = &from_server[..n?];
+            let protocol = classify(first);
+            if !protocol.blocked(block, block_http) {
+                client.write_all(first).await?;
+            }
+            (first.len(), protocol)
+        }
+    };
+    // Either side closing before saying anything ends the tunnel
+    if first == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(protocol)
+}
+
+/// The client's first bytes, if they arrive within `timeout`. Used before
+/// connecting to the target, so idle tunnels never cost an upstream socket.
+pub async fn read_first<C>(client: &mut C, timeout: Duration) -> io::Result<Vec<u8>>
+where
+    C: AsyncRead + Unpin,
+{
+    let mut first = vec![0u8; SNIFF_LEN];
+    let n = tokio::time::timeout(timeout, client.read(&mut first))
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+    if n == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    first.truncate(n);
+    Ok(first)
+}
+
+/// Lets shutdown tell live tunnels to wind down and wait until they have
+pub struct Drain {
+    started: watch::Sender<bool>,
+}
+
+impl Drain {
+    pub fn new() -> Drain {
+        Drain {
+            started: watch::Sender::new(false),
+        }
+    }
+
+    /// Held by a tunnel for as long as it is open
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.started.subscribe()
+    }
+
+    pub fn active(&self) -> usize {
+        self.started.receiver_count()
+    }
+
+    /// Tell every tunnel to drain, then wait for them all to close
+    pub async fn run(&self) {
+        self.started.send_replace(true);
+        self.started.closed().await;
+    }
+}
+
+/// Resolves once `drain` has started; never without one
+async fn draining(drain: &mut Option<watch::Receiver<bool>>) {
+    if let Some(rx) = drain
+        && rx.wait_for(|started| *started).await.is_ok()
+    {
+        return;
+    }
+    std::future::pending().await
+}
+
+/// The error a tunnel ends with once it has carried --tunnel-byte-limit bytes
+#[derive(Debug)]
+struct QuotaExceeded;
+
+impl std::fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("tunnel byte limit reached")
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+pub fn is_quota_exceeded(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|inner| inner.is::<QuotaExceeded>())
+}
+
+/// Counts bytes read into a total shared by both directions, and fails the
+/// first read after it reaches `limit`. A tunnel can go over by at most one
+/// read's worth.
+struct Metered<'a, R> {
+    inner: R,
+    used: &'a AtomicU64,
+    limit: Option<u64>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Metered<'_, R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if let Some(limit) = self.limit
+            && self.used.load(Ordering::Relaxed) >= limit
+        {
+            return Poll::Ready(Err(io::Error::other(QuotaExceeded)));
+        }
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        self.used.fetch_add((buf.filled().len() - before) as u64, Ordering::Relaxed);
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// How a tunnel is relayed once both ends are connected
+#[derive(Clone, Copy, Debug)]
+pub struct RelayOptions {
+    pub half_close_timeout: Option<Duration>,
+    /// Bytes both directions may carry together
+    pub byte_limit: Option<u64>,
+    /// Buffers per direction; more than one lets reads continue while a
+    /// write is still pending
+    pub read_buffers: usize,
+}
+
+/// Copy `reader` to `writer` until EOF through a ring of `buffers` buffers:
+/// filled ones queue for the writer while the reader fills the next free
+/// one. With a single buffer this is plain tokio::io::copy.
+async fn pipe<R, W>(reader: &mut R, writer: &mut W, buffers: usize) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    if buffers <= 1 {
+        return tokio::io::copy(reader, writer).await;
+    }
+    let (filled_tx, mut filled_rx) = mpsc::channel::<BytesMut>(buffers);
+    let (free_tx, mut free_rx) = mpsc::channel::<BytesMut>(buffers);
+    for _ in 0..buffers {
+        let _ = free_tx.try_send(BytesMut::with_capacity(PIPE_BUFFER_SIZE));
+    }
+    // Owns filled_tx, so finishing tells the writer there is nothing more
+    let read = async move {
+        while let Some(mut buf) = free_rx.recv().await {
+            buf.clear();
+            if reader.read_buf(&mut buf).await? == 0 || filled_tx.send(buf).await.is_err() {
+                break;
+            }
+        }
+        Ok::<_, io::Error>(())
+    };
+    let write = async {
+        let mut copied = 0;
+        while let Some(buf) = filled_rx.recv().await {
+            writer.write_all(&buf).await?;
+            copied += buf.len() as u64;
+            let _ = free_tx.send(buf).await;
+        }
+        writer.flush().await?;
+        Ok(copied)
+    };
+    let ((), copied) = tokio::try_join!(read, write)?;
+    Ok(copied)
+}
+
+/// Copy both directions until each side has sent EOF, passing every EOF on
+/// as a write shutdown. Once one direction is done, the other gets at most
+/// `half_close_timeout` to finish before the tunnel is torn down with a
+/// `TimedOut` error. When `drain` starts, the client is sent EOF as if the
+/// server had closed.
+/// Fails with [`is_quota_exceeded`] after `byte_limit` bytes in total.
+pub async fn relay<C, S>(
+    client: C,
+    server: S,
+    options: RelayOptions,
+    mut drain: Option<watch::Receiver<bool>>,
+    req_id: RequestId,
+    conn_log: bool,
+) -> io::Result<()>
+where
+    C: AsyncRead + AsyncWrite,
+    S: AsyncRead + AsyncWrite,
+{
+    let (client_read, mut client_write) = tokio::io::split(client);
+    let (server_read, mut server_write) = tokio::io::split(server);
+    let used = AtomicU64::new(0);
+    let mut client_read = Metered {
+        inner: client_read,
+        used: &used,
+        limit: options.byte_limit,
+    };
+    let mut server_read = Metered {
+        inner: server_read,
+        used: &used,
+        limit: options.byte_limit,
+    };
+    let upstream = async {
+        pipe(&mut client_read, &mut server_write, options.read_buffers).await?;
+        server_write.shutdown().await
+    };
+    let downstream = async {
+        tokio::select! {
+            copied = pipe(&mut server_read, &mut client_write, options.read_buffers) => {
+                copied?;
+            }
+            _ = draining(&mut drain) => {
+                if conn_log {
+                    log!("[req {}] shutting down, sending EOF to tunnel client", req_id);
+                }
+            }
+        }
+        client_write.shutdown().await
+    };
+    tokio::pin!(upstream, downstream);
+
+    let (first, rest) = tokio::select! {
+        done = &mut upstream => {
+            done?;
+            ("client", futures_util::future::Either::Left(downstream))
+        }
+        done = &mut downstream => {
+            done?;
+            ("server", futures_util::future::Either::Right(upstream))
+        }
+    };
+    if conn_log {
+        log!("[req {}] tunnel half-closed by {}", req_id, first);
+    }
+    match options.half_close_timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, rest).await {
+            Ok(done) => done,
+            Err(_) => {
+                if conn_log {
+                    log!("[req {}] tunnel half-close timed out after {:?}, closing", req_id, timeout);
+                }
+                Err(io::ErrorKind::TimedOut.into())
+            }
+        },
+        None => rest.await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_http_is_only_blocked_on_request() {
+        for first in [&b"SSH-2.0-OpenSSH_9.6"[..], b"220 mail.example ESMTP", b"220-ftp.example"] {
+            assert!(classify(first).blocked(true, false), "{:?}", classify(first));
+        }
+        for first in [&b"GET / HTTP/1.1"[..], b"PRI * HTTP/2.0\r\n"] {
+            let protocol = classify(first);
+            assert!(matches!(protocol, Protocol::Http(_)));
+            assert!(!protocol.blocked(true, false));
+            assert!(protocol.blocked(true, true));
+        }
+        assert!(!classify(&[0x16, 0x03, 0x01]).blocked(true, true));
+    }
+}
@@ -0,0 +1,80 @@
+use ::url::form_urlencoded;
+use hyper::Uri;
+use hyper::http::uri::PathAndQuery;
+
+/// Remove the whole query string (`all`) or just the parameters named in
+/// `names` from `uri`. Names are compared after form decoding, so
+/// `utm%5Fsource` and `utm_source` are the same parameter. Parameters that
+/// are kept are passed through byte-for-byte rather than re-encoded.
+pub fn strip_query(uri: Uri, all: bool, names: &[String]) -> Uri {
+    let Some(query) = uri.query() else {
+        return uri;
+    };
+
+    let kept: Vec<&str> = if all {
+        Vec::new()
+    } else {
+        query
+            .split('&')
+            .filter(|pair| match form_urlencoded::parse(pair.as_bytes()).next() {
+                Some((name, _)) => !names.iter().any(|n| *n == name),
+                None => true,
+            })
+            .collect()
+    };
+
+    let mut pq = uri.path().to_string();
+    if !kept.is_empty() {
+        pq.push('?');
+        pq.push_str(&kept.join("&"));
+    }
+
+    let mut parts = uri.into_parts();
+    // A path (plus a subset of the original query) is always a valid PathAndQuery
+    parts.path_and_query = Some(PathAndQuery::try_from(pq).unwrap());
+    Uri::from_parts(parts).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strip(uri: &str, all: bool, names: &[&str]) -> String {
+        let names: Vec<String> = names.iter().map(|n| n.to_string()).collect();
+        strip_query(uri.parse().unwrap(), all, &names).to_string()
+    }
+
+    #[test]
+    fn strips_the_whole_query() {
+        assert_eq!(strip("http://example.com/a?b=1&c=2", true, &[]), "http://example.com/a");
+        assert_eq!(strip("/a?b=1", true, &[]), "/a");
+    }
+
+    #[test]
+    fn strips_named_parameters_only() {
+        let names = ["utm_source", "utm_campaign"];
+        assert_eq!(
+            strip("http://example.com/?utm_source=x&id=7&utm_campaign=y&q=a%20b", false, &names),
+            "http://example.com/?id=7&q=a%20b"
+        );
+        assert_eq!(strip("http://example.com/?utm_source=x", false, &names), "http://example.com/");
+        assert_eq!(strip("http://example.com/?utm_sourcex=1&utm_source", false, &names), "http://example.com/?utm_sourcex=1");
+    }
+
+    #[test]
+    fn leaves_empty_and_missing_queries_alone() {
+        assert_eq!(strip("http://example.com/a", false, &["utm_source"]), "http://example.com/a");
+        assert_eq!(strip("http://example.com/a?", false, &["utm_source"]), "http://example.com/a?");
+        assert_eq!(strip("http://example.com/a?", true, &[]), "http://example.com/a");
+        assert_eq!(strip("http://example.com/a", true, &[]), "http://example.com/a");
+    }
+
+    #[test]
+    fn matches_encoded_names() {
+        let names = ["utm_source", "utm campaign"];
+        assert_eq!(
+            strip("http://example.com/?utm%5Fsource=x&utm+campaign=y&utm%20campaign=z&keep=1", false, &names),
+            "http://example.com/?keep=1"
+        );
+    }
+}
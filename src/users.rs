@@ -0,0 +1,54 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Bucket for connections when the proxy doesn't require credentials
+pub const ANONYMOUS: &str = "anonymous";
+
+/// Active connections per authenticated user, for --connection-limit-by-username
+#[derive(Debug)]
+pub struct UserLimits {
+    limit: u32,
+    active: Mutex<HashMap<String, u32>>,
+}
+
+impl UserLimits {
+    pub fn new(limit: u32) -> Arc<UserLimits> {
+        Arc::new(UserLimits {
+            limit,
+            active: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Count one more connection for `user`, or `None` if they are at the limit
+    pub fn acquire(self: &Arc<Self>, user: &str) -> Option<UserSlot> {
+        let mut active = self.active.lock().unwrap();
+        let count = active.entry(user.to_string()).or_insert(0);
+        if *count >= self.limit {
+            return None;
+        }
+        *count += 1;
+        Some(UserSlot {
+            limits: self.clone(),
+            user: user.to_string(),
+        })
+    }
+}
+
+/// One connection counted against a user; released on drop
+#[derive(Debug)]
+pub struct UserSlot {
+    limits: Arc<UserLimits>,
+    user: String,
+}
+
+impl Drop for UserSlot {
+    fn drop(&mut self) {
+        let mut active = self.limits.active.lock().unwrap();
+        if let Some(count) = active.get_mut(&self.user) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&self.user);
+            }
+        }
+    }
+}